@@ -0,0 +1,111 @@
+//! Downloads a large file from the server with and without sendfile(2) and
+//! prints the throughput of each. Run with `cargo bench --bench sendfile`,
+//! nothing else may be listening on port 4221.
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const FILE_NAME: &str = "bench.bin";
+const FILE_SIZE: usize = 256 * 1024 * 1024;
+const ROUNDS: usize = 10;
+const ADDRESS: &str = "127.0.0.1:4221";
+
+fn main() {
+    if !cfg!(target_os = "linux") {
+        println!("sendfile is only used on Linux, nothing to compare");
+        return;
+    }
+    let dir = env::temp_dir().join(format!("http-server-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("unable to create bench directory");
+    // Not all zeroes, in case the file system has a shortcut for those
+    let content = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(dir.join(FILE_NAME), content).expect("unable to write bench file");
+
+    for (name, sendfile) in [("sendfile", "true"), ("buffered", "false")] {
+        let mut server = start_server(&dir, sendfile);
+        // The first download warms the page cache
+        download();
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            download();
+        }
+        let elapsed = started.elapsed();
+        let mib = (FILE_SIZE * ROUNDS) as f64 / (1024.0 * 1024.0);
+        println!(
+            "{:<8} {:>5} x {} MiB in {:>7.3?} {:>9.1} MiB/s",
+            name,
+            ROUNDS,
+            FILE_SIZE / (1024 * 1024),
+            elapsed,
+            mib / elapsed.as_secs_f64()
+        );
+        let _ = server.kill();
+        let _ = server.wait();
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn start_server(dir: &Path, sendfile: &str) -> Child {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_codecrafters-http-server"))
+        .arg("--directory")
+        .arg(dir)
+        .args(["--sendfile", sendfile])
+        // Metadata tags, or hashing the file would be measured too
+        .args(["--strong-etags", "false"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("unable to start server");
+    for _ in 0..100 {
+        if TcpStream::connect(ADDRESS).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let _ = server.kill();
+    let _ = server.wait();
+    panic!("server didn't start listening on {}", ADDRESS);
+}
+
+/// Reads the whole file through the server, checking its length.
+fn download() {
+    let mut stream = TcpStream::connect(ADDRESS).expect("unable to connect");
+    write!(
+        stream,
+        "GET /files/{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        FILE_NAME
+    )
+    .expect("unable to send request");
+    let mut reader = BufReader::new(stream);
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).expect("unable to read head");
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    assert_eq!(content_length, Some(FILE_SIZE), "unexpected response head");
+    let mut buf = vec![0; 256 * 1024];
+    let mut received = 0;
+    while received < FILE_SIZE {
+        match reader.read(&mut buf).expect("unable to read body") {
+            0 => break,
+            n => received += n,
+        }
+    }
+    assert_eq!(received, FILE_SIZE, "body cut short");
+}
@@ -0,0 +1,148 @@
+use std::{io::SeekFrom, pin::Pin};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+};
+
+use crate::HTTP_LINE_ENDING;
+
+/// Largest chunk written when sending a body with the chunked transfer coding.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Response payload. Only [`Body::Full`] lives in memory, every other
+/// variant is written to the connection piece by piece as it is produced.
+pub enum Body {
+    Empty,
+    Full(Vec<u8>),
+    /// Bytes produced by any async reader: a proxied upstream response, a
+    /// pipe, or content generated by a task (see [`Body::channel`]).
+    Stream {
+        reader: Pin<Box<dyn AsyncRead + Send>>,
+        length: Option<u64>,
+    },
+    /// `len` bytes of `file`, starting at `offset`.
+    File {
+        file: File,
+        offset: u64,
+        len: u64,
+    },
+}
+
+impl Body {
+    /// A stream whose length isn't known up front.
+    #[allow(dead_code)]
+    pub fn stream<R>(reader: R) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        Body::Stream {
+            reader: Box::pin(reader),
+            length: None,
+        }
+    }
+    /// A stream that is known to produce exactly `length` bytes.
+    #[allow(dead_code)]
+    pub fn sized_stream<R>(reader: R, length: u64) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        Body::Stream {
+            reader: Box::pin(reader),
+            length: Some(length),
+        }
+    }
+    /// A body fed by whoever holds the returned writer, typically a spawned
+    /// task generating content. The response ends when the writer is dropped.
+    #[allow(dead_code)]
+    pub fn channel(buffer_size: usize) -> (DuplexStream, Self) {
+        let (writer, reader) = tokio::io::duplex(buffer_size);
+        (writer, Body::stream(reader))
+    }
+    pub fn file(file: std::fs::File, offset: u64, len: u64) -> Self {
+        Body::File {
+            file: File::from_std(file),
+            offset,
+            len,
+        }
+    }
+    /// Number of bytes the body will produce, if known before sending it.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Full(bytes) => Some(bytes.len() as u64),
+            Body::Stream { length, .. } => *length,
+            Body::File { len, .. } => Some(*len),
+        }
+    }
+    /// The body as a reader, for consumers that transform it on the way
+    /// out.
+    pub async fn into_reader(self) -> std::io::Result<Pin<Box<dyn AsyncRead + Send>>> {
+        Ok(match self {
+            Body::Empty => Box::pin(tokio::io::empty()),
+            Body::Full(bytes) => Box::pin(std::io::Cursor::new(bytes)),
+            Body::Stream { reader, .. } => reader,
+            Body::File {
+                mut file,
+                offset,
+                len,
+            } => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                Box::pin(file.take(len))
+            }
+        })
+    }
+    /// Writes the body using the chunked transfer coding, without the
+    /// terminating last-chunk so the caller can append trailers.
+    pub async fn write_chunks_to<W>(self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut reader = self.into_reader().await?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            writer
+                .write_all(format!("{:X}{}", n, HTTP_LINE_ENDING).as_bytes())
+                .await?;
+            writer.write_all(&buf[..n]).await?;
+            writer.write_all(HTTP_LINE_ENDING.as_bytes()).await?;
+        }
+    }
+    pub async fn write_to<W>(self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match self {
+            Body::Empty => {}
+            Body::Full(bytes) => writer.write_all(&bytes).await?,
+            Body::Stream { mut reader, .. } => {
+                tokio::io::copy(&mut reader, writer).await?;
+            }
+            Body::File {
+                mut file,
+                offset,
+                len,
+            } => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                tokio::io::copy(&mut file.take(len), writer).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Full(bytes)
+    }
+}
+
+impl From<Option<Vec<u8>>> for Body {
+    fn from(bytes: Option<Vec<u8>>) -> Self {
+        bytes.map_or(Body::Empty, Body::Full)
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    fs::File,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    date, encoding::Encoding, etag::ETag, header::Header, method::Method, request::Request,
+};
+
+/// Validators of the current representation of a resource.
+#[derive(Debug, Default, Clone)]
+pub struct Validators {
+    pub etag: Option<ETag>,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// A strong `ETag` hashes the whole file, a weak one only looks at its
+    /// metadata, see [`ETag::from_file`] and [`ETag::from_metadata`].
+    pub async fn from_file(file: &File, strong_etag: bool) -> std::io::Result<Self> {
+        let metadata = file.metadata()?;
+        let etag = match strong_etag {
+            true => ETag::from_file(file).await?,
+            false => ETag::from_metadata(&metadata),
+        };
+        Ok(Validators {
+            etag: Some(etag),
+            last_modified: metadata.modified().ok(),
+        })
+    }
+
+    /// Whether `other` is the tag of the current content, in any of the
+    /// codings it may have been sent with (see [`ETag::encoded`]). A client
+    /// that downloaded it compressed can then update it with `If-Match`.
+    fn matches(&self, other: &ETag, eq: fn(&ETag, &ETag) -> bool) -> bool {
+        self.etag.as_ref().is_some_and(|etag| {
+            eq(other, etag)
+                || Encoding::ALL
+                    .iter()
+                    .any(|encoding| eq(other, &etag.encoded(*encoding)))
+        })
+    }
+}
+
+/// Preconditions of a request (RFC 9110 section 13), kept apart from the
+/// request so they can be checked once the handler has consumed it.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    /// Only `GET` and `HEAD` can be answered with `304 Not Modified`.
+    cacheable: bool,
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<SystemTime>,
+    if_unmodified_since: Option<SystemTime>,
+}
+
+impl Conditions {
+    pub fn from_request(request: &Request) -> Self {
+        let date = |header: Header| request.headers.get(header.to_str()).and_then(date::parse);
+        Conditions {
+            cacheable: matches!(request.method, Method::Get | Method::Head),
+            if_match: request.headers.get_joined(Header::IfMatch.to_str()),
+            if_none_match: request.headers.get_joined(Header::IfNoneMatch.to_str()),
+            if_modified_since: date(Header::IfModifiedSince),
+            if_unmodified_since: date(Header::IfUnmodifiedSince),
+        }
+    }
+
+    pub fn is_cacheable(&self) -> bool {
+        self.cacheable
+    }
+
+    /// Whether the request must be refused with `412 Precondition Failed`.
+    /// `current` is `None` when the resource has no representation yet, e.g.
+    /// a file about to be created (RFC 9110 section 13.2.2, steps 1 to 3).
+    pub fn precondition_failed(&self, current: Option<&Validators>) -> bool {
+        if let Some(if_match) = &self.if_match {
+            // `If-Match` needs a strong comparison
+            let matches = match current {
+                None => false,
+                Some(_) if if_match.trim() == "*" => true,
+                Some(current) => ETag::parse_list(if_match).is_some_and(|etags| {
+                    etags
+                        .iter()
+                        .any(|other| current.matches(other, ETag::strong_eq))
+                }),
+            };
+            if !matches {
+                return true;
+            }
+        } else if let (Some(since), Some(last_modified)) = (
+            self.if_unmodified_since,
+            current.and_then(|current| current.last_modified),
+        ) {
+            if unix_secs(last_modified) > unix_secs(since) {
+                return true;
+            }
+        }
+        // For `GET` and `HEAD` a match means `304 Not Modified` instead
+        !self.cacheable && self.none_match_fails(current)
+    }
+
+    /// Whether the client's cached copy of the `current` representation is
+    /// still current, i.e. it can get `304 Not Modified`.
+    pub fn not_modified(&self, current: &Validators) -> bool {
+        if !self.cacheable {
+            return false;
+        }
+        // `If-None-Match` takes precedence (RFC 9110 section 13.2.2)
+        if self.if_none_match.is_some() {
+            return self.none_match_fails(Some(current));
+        }
+        match (self.if_modified_since, current.last_modified) {
+            // HTTP dates have a one second resolution
+            (Some(since), Some(last_modified)) => unix_secs(last_modified) <= unix_secs(since),
+            _ => false,
+        }
+    }
+
+    /// Whether `If-None-Match` is present and matches `current`, with the
+    /// weak comparison.
+    fn none_match_fails(&self, current: Option<&Validators>) -> bool {
+        let (Some(if_none_match), Some(current)) = (&self.if_none_match, current) else {
+            return false;
+        };
+        if_none_match.trim() == "*"
+            || ETag::parse_list(if_none_match).is_some_and(|etags| {
+                etags.iter().any(|other| match self.cacheable {
+                    // A `304` is only for the very representation being sent
+                    true => current
+                        .etag
+                        .as_ref()
+                        .is_some_and(|etag| other.weak_eq(etag)),
+                    false => current.matches(other, ETag::weak_eq),
+                })
+            })
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current(tag: &str) -> Validators {
+        Validators {
+            etag: ETag::strong(tag),
+            last_modified: None,
+        }
+    }
+
+    fn upload(if_match: Option<&str>, if_none_match: Option<&str>) -> Conditions {
+        Conditions {
+            cacheable: false,
+            if_match: if_match.map(str::to_string),
+            if_none_match: if_none_match.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn if_match_accepts_tags_of_every_coding() {
+        let current = current("abc");
+        for if_match in [r#""abc""#, r#""abc-gzip""#, r#""x", "abc-zstd""#, "*"] {
+            let conditions = upload(Some(if_match), None);
+            assert!(
+                !conditions.precondition_failed(Some(&current)),
+                "{}",
+                if_match
+            );
+        }
+        for if_match in [r#""abd""#, r#""abc-compress""#, r#"W/"abc-gzip""#] {
+            let conditions = upload(Some(if_match), None);
+            assert!(
+                conditions.precondition_failed(Some(&current)),
+                "{}",
+                if_match
+            );
+        }
+        assert!(upload(Some("*"), None).precondition_failed(None));
+    }
+
+    #[test]
+    fn if_none_match_refuses_uploads_over_any_coding() {
+        let current = current("abc");
+        assert!(upload(None, Some(r#"W/"abc-br""#)).precondition_failed(Some(&current)));
+        assert!(upload(None, Some("*")).precondition_failed(Some(&current)));
+        assert!(!upload(None, Some("*")).precondition_failed(None));
+        assert!(!upload(None, Some(r#""abd""#)).precondition_failed(Some(&current)));
+    }
+
+    #[test]
+    fn not_modified_only_for_the_representation_sent() {
+        let conditions = |if_none_match: &str| Conditions {
+            cacheable: true,
+            if_none_match: Some(if_none_match.to_string()),
+            ..Default::default()
+        };
+        let gzipped = current("abc-gzip");
+        assert!(conditions(r#""abc-gzip""#).not_modified(&gzipped));
+        assert!(conditions(r#"W/"abc-gzip""#).not_modified(&gzipped));
+        assert!(!conditions(r#""abc""#).not_modified(&gzipped));
+        assert!(!conditions(r#""abc-gzip""#).not_modified(&current("abc")));
+    }
+}
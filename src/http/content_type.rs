@@ -1,13 +1,501 @@
-#[derive(Copy, Clone)]
-pub enum ContentType {
-    Plain,
-    OctetStream,
+use std::{borrow::Cow, fmt::Display, path::Path, str::FromStr};
+
+use itertools::Itertools;
+
+use super::{
+    header_map::{is_tchar, most_preferred, parse_qvalue},
+    status_code::StatusCode,
+};
+
+/// A media type (RFC 9110 section 8.3.1): `type/subtype` followed by
+/// optional `; name=value` parameters such as `charset` or `boundary`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentType {
+    /// `type/subtype`, always lower case.
+    essence: Cow<'static, str>,
+    /// Parameter names are lower case, values are kept as sent.
+    params: Vec<(String, String)>,
 }
+
 impl ContentType {
-    pub fn to_str(&self) -> &str {
-        match self {
-            ContentType::Plain => "text/plain",
-            ContentType::OctetStream => "application/octet-stream",
+    pub const PLAIN: ContentType = ContentType::from_static("text/plain");
+    pub const OCTET_STREAM: ContentType = ContentType::from_static("application/octet-stream");
+    pub const HTML: ContentType = ContentType::from_static("text/html");
+    pub const JSON: ContentType = ContentType::from_static("application/json");
+
+    /// `essence` has to be a lower case `type/subtype` without parameters.
+    pub const fn from_static(essence: &'static str) -> Self {
+        ContentType {
+            essence: Cow::Borrowed(essence),
+            params: Vec::new(),
+        }
+    }
+
+    /// `type/subtype`, without parameters.
+    pub fn essence(&self) -> &str {
+        &self.essence
+    }
+    pub fn main_type(&self) -> &str {
+        self.essence.split('/').next().unwrap_or_default()
+    }
+    pub fn subtype(&self) -> &str {
+        self.essence.split('/').nth(1).unwrap_or_default()
+    }
+
+    #[allow(dead_code)]
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    /// Sets parameter `name`, replacing an existing value.
+    pub fn with_param(mut self, name: &str, value: &str) -> Self {
+        let name = name.to_ascii_lowercase();
+        self.params.retain(|(key, _)| *key != name);
+        self.params.push((name, value.to_string()));
+        self
+    }
+    #[allow(dead_code)]
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+    pub fn with_charset(self, charset: &str) -> Self {
+        self.with_param("charset", charset)
+    }
+
+    /// Whether compressing content of this type is worth it. Most image,
+    /// audio, video and archive formats are compressed already.
+    pub fn is_compressible(&self) -> bool {
+        match self.main_type() {
+            "text" => true,
+            "image" => matches!(self.subtype(), "svg+xml" | "bmp" | "vnd.microsoft.icon"),
+            "audio" | "video" => false,
+            "font" => matches!(self.subtype(), "ttf" | "otf"),
+            _ => !INCOMPRESSIBLE.contains(&self.essence()),
+        }
+    }
+
+    /// Whether `content_type` falls within `self` used as a media range:
+    /// `*/*`, `text/*` or a full type whose parameters all have to match.
+    pub fn includes(&self, content_type: &ContentType) -> bool {
+        let type_matches = match (self.main_type(), self.subtype()) {
+            ("*", "*") => true,
+            (main_type, "*") => main_type == content_type.main_type(),
+            _ => self.essence == content_type.essence,
+        };
+        type_matches
+            && self.params.iter().all(|(name, value)| {
+                content_type
+                    .param(name)
+                    .is_some_and(|other| other.eq_ignore_ascii_case(value))
+            })
+    }
+
+    /// How narrow `self` is as a media range, the most specific range that
+    /// matches a type decides its weight (RFC 9110 section 12.5.1).
+    fn specificity(&self) -> usize {
+        match (self.main_type(), self.subtype()) {
+            ("*", "*") => 0,
+            (_, "*") => 1,
+            _ => 2 + self.params.len(),
+        }
+    }
+
+    /// Media type registered for a file extension (without the dot), case
+    /// insensitive.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        let extension = extension.to_ascii_lowercase();
+        let essence = EXTENSIONS
+            .iter()
+            .find(|(ext, _)| *ext == extension)
+            .map(|(_, essence)| *essence)?;
+        let content_type = ContentType::from_static(essence);
+        // Text we serve from disk is assumed to be UTF-8
+        if essence.starts_with("text/") {
+            return Some(content_type.with_charset("utf-8"));
+        }
+        Some(content_type)
+    }
+
+    /// Media type for a file based on its extension,
+    /// `application/octet-stream` when it isn't known.
+    pub fn from_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(ContentType::from_extension)
+            .unwrap_or(ContentType::OCTET_STREAM)
+    }
+}
+
+/// Picks the representation to send out of `available`, most preferred
+/// first, according to the request's `Accept` (RFC 9110 section 12.5.1).
+/// Without `Accept` the first one is sent. Fails with `406 Not Acceptable`
+/// when the client accepts none of them.
+pub fn negotiate(
+    accept: Option<&str>,
+    available: &[ContentType],
+) -> Result<ContentType, StatusCode> {
+    let ranges = accept
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(parse_media_range)
+        .collect_vec();
+    if ranges.is_empty() {
+        return available.first().cloned().ok_or(StatusCode::NotAcceptable);
+    }
+    most_preferred(available.iter().filter_map(|content_type| {
+        let (_, weight) = ranges
+            .iter()
+            .filter(|(range, _)| range.includes(content_type))
+            .max_by_key(|(range, _)| range.specificity())?;
+        Some((content_type, *weight))
+    }))
+    .map(|(content_type, _)| content_type.clone())
+    .ok_or(StatusCode::NotAcceptable)
+}
+
+/// One element of `Accept`: a media range and its weight in thousandths.
+/// Malformed elements are skipped rather than failing the whole field.
+fn parse_media_range(element: &str) -> Option<(ContentType, u16)> {
+    let element = element.trim_matches([' ', '\t']);
+    if element.is_empty() {
+        return None;
+    }
+    let mut range = ContentType::from_str(element).ok()?;
+    if range.main_type() == "*" && range.subtype() != "*" {
+        return None;
+    }
+    // `q` separates media type parameters from accept extensions
+    let weight = match range.params.iter().position(|(name, _)| name == "q") {
+        Some(i) => {
+            let weight = parse_qvalue(&range.params[i].1)?;
+            range.params.truncate(i);
+            weight
+        }
+        None => 1000,
+    };
+    Some((range, weight))
+}
+
+impl Display for ContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.essence)?;
+        for (name, value) in &self.params {
+            if !value.is_empty() && value.bytes().all(is_tchar) {
+                write!(f, "; {}={}", name, value)?;
+            } else {
+                let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+                write!(f, "; {}=\"{}\"", name, escaped)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = String;
+
+    /// Parses `type "/" subtype *( OWS ";" OWS parameter )`, where parameter
+    /// values can be tokens or quoted strings.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid media type `{}`", s);
+        let (essence, mut rest) = match s.find(';') {
+            Some(i) => (&s[..i], &s[i..]),
+            None => (s, ""),
+        };
+        let essence = essence.trim_matches([' ', '\t']);
+        let (main_type, subtype) = essence.split_once('/').ok_or_else(invalid)?;
+        if main_type.is_empty()
+            || subtype.is_empty()
+            || !main_type.bytes().all(is_tchar)
+            || !subtype.bytes().all(is_tchar)
+        {
+            return Err(invalid());
+        }
+        let mut content_type = ContentType {
+            essence: Cow::Owned(essence.to_ascii_lowercase()),
+            params: vec![],
+        };
+        loop {
+            rest = rest.trim_start_matches([' ', '\t']);
+            let Some(param) = rest.strip_prefix(';') else {
+                break;
+            };
+            let param = param.trim_start_matches([' ', '\t']);
+            // Tolerate a trailing `;`
+            if param.is_empty() {
+                rest = param;
+                break;
+            }
+            let (name, value) = param.split_once('=').ok_or_else(invalid)?;
+            if name.is_empty() || !name.bytes().all(is_tchar) {
+                return Err(invalid());
+            }
+            let (value, remaining) = match value.strip_prefix('"') {
+                Some(quoted) => parse_quoted_string(quoted).ok_or_else(invalid)?,
+                None => {
+                    let end = value.find(';').unwrap_or(value.len());
+                    let token = value[..end].trim_end_matches([' ', '\t']);
+                    if token.is_empty() || !token.bytes().all(is_tchar) {
+                        return Err(invalid());
+                    }
+                    (token.to_string(), &value[end..])
+                }
+            };
+            content_type = content_type.with_param(name, &value);
+            rest = remaining;
+        }
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+        Ok(content_type)
+    }
+}
+
+/// Reads a quoted-string whose opening quote was already consumed. Returns
+/// the unescaped value and whatever follows the closing quote.
+fn parse_quoted_string(s: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 1..])),
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Compressed formats that aren't covered by their top-level type.
+const INCOMPRESSIBLE: &[&str] = &[
+    "application/zip",
+    "application/gzip",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/zstd",
+    "application/x-brotli",
+    "application/pdf",
+];
+
+/// File extension to media type, mostly the common types from the IANA
+/// media type registry.
+const EXTENSIONS: &[(&str, &str)] = &[
+    // Text & documents
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    // Images
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("bmp", "image/bmp"),
+    // Audio & video
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mov", "video/quicktime"),
+    // Fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    // Archives
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("7z", "application/x-7z-compressed"),
+    ("zst", "application/zstd"),
+    ("br", "application/x-brotli"),
+];
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const AVAILABLE: [ContentType; 3] = [ContentType::JSON, ContentType::HTML, ContentType::PLAIN];
+
+    fn parse(content_type: &str) -> ContentType {
+        ContentType::from_str(content_type).unwrap()
+    }
+
+    #[test]
+    fn without_accept_sends_the_first() {
+        assert_eq!(negotiate(None, &AVAILABLE), Ok(ContentType::JSON));
+        assert_eq!(negotiate(Some(""), &AVAILABLE), Ok(ContentType::JSON));
+        assert_eq!(negotiate(None, &[]), Err(StatusCode::NotAcceptable));
+    }
+
+    #[test]
+    fn picks_the_highest_weight_then_our_preference() {
+        let accept = "text/plain, text/html;q=0.9, application/json;q=0.5";
+        assert_eq!(negotiate(Some(accept), &AVAILABLE), Ok(ContentType::PLAIN));
+        assert_eq!(
+            negotiate(Some("text/plain, text/html"), &AVAILABLE),
+            Ok(ContentType::HTML)
+        );
+        assert_eq!(negotiate(Some("*/*"), &AVAILABLE), Ok(ContentType::JSON));
+        // Malformed elements are skipped
+        assert_eq!(
+            negotiate(Some("*/html, text/plain;q=2, text/html"), &AVAILABLE),
+            Ok(ContentType::HTML)
+        );
+    }
+
+    #[test]
+    fn excludes_q_zero() {
+        assert_eq!(
+            negotiate(Some("application/json;q=0, */*"), &AVAILABLE),
+            Ok(ContentType::HTML)
+        );
+        assert_eq!(
+            negotiate(Some("*/*;q=0"), &AVAILABLE),
+            Err(StatusCode::NotAcceptable)
+        );
+        assert_eq!(
+            negotiate(Some("image/png"), &AVAILABLE),
+            Err(StatusCode::NotAcceptable)
+        );
+    }
+
+    #[test]
+    fn the_most_specific_range_decides() {
+        // text/html matches all three ranges, only the narrowest counts
+        let accept = "*/*;q=0.8, text/*;q=0.1, text/html;q=0.5, text/plain;q=0";
+        assert_eq!(negotiate(Some(accept), &AVAILABLE), Ok(ContentType::JSON));
+        let accept = "text/*;q=0.1, text/html;q=0.5";
+        assert_eq!(negotiate(Some(accept), &AVAILABLE), Ok(ContentType::HTML));
+        let accept = "text/*, text/html;q=0";
+        assert_eq!(negotiate(Some(accept), &AVAILABLE), Ok(ContentType::PLAIN));
+    }
+
+    #[test]
+    fn matches_parameters() {
+        let available = [ContentType::HTML, ContentType::PLAIN.with_charset("utf-8")];
+        // A range with parameters only matches types that have them
+        assert_eq!(
+            negotiate(Some("text/html;level=1, text/plain;q=0.1"), &available),
+            Ok(available[1].clone())
+        );
+        assert_eq!(
+            negotiate(Some("text/plain;charset=UTF-8"), &available),
+            Ok(available[1].clone())
+        );
+        assert_eq!(
+            negotiate(Some("text/plain;charset=ascii"), &available),
+            Err(StatusCode::NotAcceptable)
+        );
+        assert!(parse("text/*").includes(&parse("text/csv; charset=utf-8")));
+        assert!(!parse("text/*; charset=utf-8").includes(&parse("text/csv")));
+        assert!(!parse("text/*").includes(&parse("application/json")));
+    }
+    #[test]
+    fn parses_parameters() {
+        let content_type = parse("Text/HTML ; Charset=UTF-8;level=1");
+        assert_eq!(content_type.essence(), "text/html");
+        // Names are case insensitive, values are kept as sent
+        assert_eq!(content_type.charset(), Some("UTF-8"));
+        assert_eq!(content_type.param("LEVEL"), Some("1"));
+        assert_eq!(parse("text/plain;").essence(), "text/plain");
+
+        let content_type = parse(r#"multipart/form-data; boundary="a b;c=\"d\"\\e"; x=y"#);
+        assert_eq!(content_type.param("boundary"), Some(r#"a b;c="d"\e"#));
+        assert_eq!(content_type.param("x"), Some("y"));
+        // The last of a repeated parameter wins
+        assert_eq!(parse("text/plain; a=1; A=2").param("a"), Some("2"));
+    }
+
+    #[test]
+    fn rejects_malformed_types() {
+        for content_type in [
+            "",
+            "text",
+            "text/",
+            "/html",
+            "text/html/x",
+            "te xt/html",
+            "text/ht(ml",
+            "text/html; charset",
+            "text/html; =utf-8",
+            "text/html; charset=",
+            "text/html; charset=utf 8",
+            "text/html; char set=utf-8",
+            r#"text/html; charset="utf-8"#,
+            r#"text/html; charset="utf-8" x"#,
+            r#"text/html; charset="utf-8\"#,
+        ] {
+            assert!(
+                ContentType::from_str(content_type).is_err(),
+                "{:?}",
+                content_type
+            );
+        }
+    }
+
+    #[test]
+    fn quotes_parameters_when_needed() {
+        assert_eq!(
+            ContentType::PLAIN.with_charset("utf-8").to_string(),
+            "text/plain; charset=utf-8"
+        );
+        let content_type = ContentType::from_static("multipart/mixed")
+            .with_param("boundary", r#"a b"c\d"#)
+            .with_param("empty", "");
+        assert_eq!(
+            content_type.to_string(),
+            r#"multipart/mixed; boundary="a b\"c\\d"; empty="""#
+        );
+        // And parses back to the same type
+        assert_eq!(parse(&content_type.to_string()), content_type);
+    }
+
+    #[test]
+    fn looks_up_extensions() {
+        assert_eq!(
+            ContentType::from_extension("HTML"),
+            Some(ContentType::HTML.with_charset("utf-8"))
+        );
+        assert_eq!(ContentType::from_extension("Json"), Some(ContentType::JSON));
+        assert_eq!(ContentType::from_extension("unknown"), None);
+        for (path, content_type) in [
+            ("site/style.CSS", "text/css; charset=utf-8"),
+            ("notes.md", "text/markdown; charset=utf-8"),
+            ("logo.png", "image/png"),
+            ("archive.tar.xyz", "application/octet-stream"),
+            ("Makefile", "application/octet-stream"),
+            (".png", "application/octet-stream"),
+        ] {
+            assert_eq!(
+                ContentType::from_path(Path::new(path)).to_string(),
+                content_type,
+                "{}",
+                path
+            );
         }
     }
 }
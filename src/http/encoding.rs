@@ -1,14 +1,326 @@
-use std::str::FromStr;
+use std::{io::Write, str::FromStr};
 
-#[derive(Clone)]
+use flate2::write::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder};
+use zstd::stream::raw::Operation;
+
+use super::{
+    header_map::{most_preferred, parse_qvalue},
+    status_code::StatusCode,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Encoding {
     Gzip,
+    /// The zlib format (RFC 1950), which is what `deflate` means in HTTP.
+    Deflate,
+    Brotli,
+    Zstd,
 }
 
 impl Encoding {
-    pub fn to_str(&self) -> &str {
+    /// Supported codings, most preferred first. Used to break ties between
+    /// codings the client accepts equally.
+    pub const ALL: [Encoding; 4] = [
+        Encoding::Zstd,
+        Encoding::Brotli,
+        Encoding::Gzip,
+        Encoding::Deflate,
+    ];
+
+    pub fn to_str(self) -> &'static str {
         match self {
             Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Compresses `data` in one go.
+    pub fn encode(self, data: &[u8], level: CompressionLevel) -> std::io::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(self, level)?;
+        let mut encoded = encoder.write(data)?;
+        encoded.extend(encoder.finish()?);
+        Ok(encoded)
+    }
+}
+
+/// Trade-off between speed and compressed size, mapped to each codec's own
+/// scale.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CompressionLevel {
+    #[allow(dead_code)]
+    Fastest,
+    #[default]
+    Default,
+    #[allow(dead_code)]
+    Best,
+}
+
+impl CompressionLevel {
+    fn flate2(self) -> flate2::Compression {
+        match self {
+            CompressionLevel::Fastest => flate2::Compression::fast(),
+            CompressionLevel::Default => flate2::Compression::default(),
+            CompressionLevel::Best => flate2::Compression::best(),
+        }
+    }
+    fn brotli(self) -> u32 {
+        match self {
+            CompressionLevel::Fastest => 1,
+            // 11 is far too slow to run on every response
+            CompressionLevel::Default => 5,
+            CompressionLevel::Best => 11,
+        }
+    }
+    fn zstd(self) -> i32 {
+        match self {
+            CompressionLevel::Fastest => 1,
+            CompressionLevel::Default => zstd::DEFAULT_COMPRESSION_LEVEL,
+            CompressionLevel::Best => 19,
+        }
+    }
+}
+
+/// Compresses a body piece by piece, handing out the output as soon as the
+/// codec produces it.
+pub enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding, level: CompressionLevel) -> std::io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), level.flate2())),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), level.flate2())),
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                level.brotli(),
+                22,
+            ))),
+            Encoding::Zstd => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), level.zstd())?)
+            }
+        })
+    }
+
+    /// Feeds `data` to the codec, returns the compressed bytes ready so far.
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let output = match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(output))
+    }
+
+    /// Ends the stream, returns the remaining compressed bytes.
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Output produced per step by the brotli and zstd decoders.
+const DECODE_BUF: usize = 32 * 1024;
+
+type BrotliDecoderState = brotli::BrotliState<
+    brotli::HeapAlloc<u8>,
+    brotli::HeapAlloc<u32>,
+    brotli::HeapAlloc<brotli::HuffmanCode>,
+>;
+
+/// Decompresses a body piece by piece, the counterpart of [`Encoder`].
+pub enum Decoder {
+    Gzip(GzDecoder<Vec<u8>>),
+    Deflate(ZlibDecoder<Vec<u8>>),
+    /// Driven step by step rather than through a writer, which would inflate
+    /// a whole input at once however large the output.
+    Brotli {
+        state: Box<BrotliDecoderState>,
+        done: bool,
+    },
+    /// The streaming zstd writer can't tell a truncated frame from a
+    /// complete one, the raw decoder reports when a frame ends.
+    Zstd {
+        decoder: Box<zstd::stream::raw::Decoder<'static>>,
+        frame_done: bool,
+    },
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> std::io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Decoder::Gzip(GzDecoder::new(Vec::new())),
+            Encoding::Deflate => Decoder::Deflate(ZlibDecoder::new(Vec::new())),
+            Encoding::Brotli => Decoder::Brotli {
+                state: Box::new(brotli::BrotliState::new(
+                    brotli::HeapAlloc::default(),
+                    brotli::HeapAlloc::default(),
+                    brotli::HeapAlloc::default(),
+                )),
+                done: false,
+            },
+            Encoding::Zstd => Decoder::Zstd {
+                decoder: Box::new(zstd::stream::raw::Decoder::new()?),
+                frame_done: false,
+            },
+        })
+    }
+
+    /// Feeds compressed `data` to the codec, returns what it decompressed
+    /// but never more than `budget` bytes: once the output reaches it, the
+    /// rest of `data` is dropped and the caller is expected to give up.
+    /// gzip and deflate inflate at most about 1:1000, so their output is
+    /// bounded by the size of `data`. Brotli and zstd go far beyond and stop
+    /// as soon as the budget is spent.
+    pub fn write(&mut self, data: &[u8], budget: usize) -> std::io::Result<Vec<u8>> {
+        let mut output = match self {
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                std::mem::take(decoder.get_mut())
+            }
+            Decoder::Deflate(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                std::mem::take(decoder.get_mut())
+            }
+            Decoder::Brotli { state, done } => {
+                let mut output = vec![];
+                let mut buf = vec![0; DECODE_BUF];
+                let (mut available_in, mut input_offset) = (data.len(), 0);
+                loop {
+                    let (mut available_out, mut output_offset, mut total_out) = (buf.len(), 0, 0);
+                    let result = brotli::BrotliDecompressStream(
+                        &mut available_in,
+                        &mut input_offset,
+                        data,
+                        &mut available_out,
+                        &mut output_offset,
+                        &mut buf,
+                        &mut total_out,
+                        state,
+                    );
+                    output.extend_from_slice(&buf[..output_offset]);
+                    match result {
+                        brotli::BrotliResult::NeedsMoreOutput if output.len() < budget => {}
+                        brotli::BrotliResult::NeedsMoreOutput
+                        | brotli::BrotliResult::NeedsMoreInput => break output,
+                        brotli::BrotliResult::ResultSuccess if available_in == 0 => {
+                            *done = true;
+                            break output;
+                        }
+                        // Failed, or data follows the end of the stream
+                        _ => return Err(std::io::ErrorKind::InvalidData.into()),
+                    }
+                }
+            }
+            Decoder::Zstd {
+                decoder,
+                frame_done,
+            } => {
+                let mut input = data;
+                let mut output = vec![];
+                let mut buf = vec![0; DECODE_BUF];
+                loop {
+                    let status = decoder.run_on_buffers(input, &mut buf)?;
+                    input = &input[status.bytes_read..];
+                    output.extend_from_slice(&buf[..status.bytes_written]);
+                    *frame_done = status.remaining == 0;
+                    // Keep going while input is left or the output filled up
+                    if (input.is_empty() && status.bytes_written < buf.len())
+                        || output.len() >= budget
+                    {
+                        break output;
+                    }
+                }
+            }
+        };
+        output.truncate(budget);
+        Ok(output)
+    }
+
+    /// Ends the stream, returns the remaining output. Fails if the
+    /// compressed data was cut short.
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        let truncated = || std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        match self {
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Deflate(decoder) => decoder.finish(),
+            Decoder::Brotli { done, .. } if done => Ok(vec![]),
+            Decoder::Brotli { .. } => Err(truncated()),
+            Decoder::Zstd { frame_done, .. } if frame_done => Ok(vec![]),
+            Decoder::Zstd { .. } => Err(truncated()),
+        }
+    }
+}
+
+/// Server-wide response compression, see
+/// [`HttpServerBuilder::compression`](super::server::HttpServerBuilder::compression).
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    pub enabled: bool,
+    /// Bodies smaller than this aren't worth the CPU and the header bytes.
+    /// Bodies of unknown length are always compressed.
+    pub min_size: u64,
+    pub level: CompressionLevel,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            enabled: true,
+            min_size: 1024,
+            level: CompressionLevel::default(),
+        }
+    }
+}
+
+/// Decompression of request bodies, see
+/// [`HttpServerBuilder::decompress_requests`](super::server::HttpServerBuilder::decompress_requests).
+#[derive(Debug, Clone)]
+pub struct DecompressionOptions {
+    pub enabled: bool,
+    /// Largest decompressed body handlers can read.
+    pub max_size: u64,
+    /// Largest decompressed to compressed size ratio, beyond the first
+    /// [`RATIO_GRACE`] bytes. Legitimate data rarely compresses better than
+    /// 1:100, decompression bombs go way past 1:1000.
+    pub max_ratio: u64,
+}
+
+/// Decompressed bytes allowed before [`DecompressionOptions::max_ratio`] is
+/// enforced, so small but very repetitive bodies still get through.
+pub const RATIO_GRACE: u64 = 1024 * 1024;
+
+impl Default for DecompressionOptions {
+    fn default() -> Self {
+        DecompressionOptions {
+            enabled: false,
+            max_size: 64 * 1024 * 1024,
+            max_ratio: 100,
         }
     }
 }
@@ -18,8 +330,248 @@ impl FromStr for Encoding {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
-            "gzip" => Ok(Encoding::Gzip),
+            // `x-gzip` is an alias kept for compatibility (RFC 9110 section 8.4.1.3)
+            "gzip" | "x-gzip" => Ok(Encoding::Gzip),
+            "deflate" => Ok(Encoding::Deflate),
+            "br" => Ok(Encoding::Brotli),
+            "zstd" => Ok(Encoding::Zstd),
             _ => Err("Unsupported Encoding".to_string()),
         }
     }
 }
+
+/// Picks the content coding for a response from the request's
+/// `Accept-Encoding` (RFC 9110 section 12.5.3). `Ok(None)` means the body is
+/// sent as is. With `prefer_identity`, e.g. for bodies not worth compressing,
+/// a coding is only picked when the client refuses `identity`. Fails with
+/// `406 Not Acceptable` when the client rules out `identity` and every coding
+/// we support.
+pub fn negotiate(
+    accept_encoding: Option<&str>,
+    prefer_identity: bool,
+) -> Result<Option<Encoding>, StatusCode> {
+    let Some(accept_encoding) = accept_encoding else {
+        return Ok(None);
+    };
+    // (coding, weight in thousandths)
+    let mut weights = vec![];
+    for element in accept_encoding.split(',') {
+        let mut parts = element
+            .split(';')
+            .map(|part| part.trim_matches([' ', '\t']));
+        let coding = parts.next().unwrap_or_default().to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let mut weight = Some(1000);
+        for param in parts {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim_end().eq_ignore_ascii_case("q") {
+                    weight = parse_qvalue(value.trim_start());
+                }
+            }
+        }
+        // Ignore elements with a malformed weight rather than the whole field
+        if let Some(weight) = weight {
+            weights.push((coding, weight));
+        }
+    }
+    let weight_of = |names: &[&str]| {
+        weights
+            .iter()
+            .find(|(coding, _)| names.contains(&coding.as_str()))
+            .or_else(|| weights.iter().find(|(coding, _)| coding == "*"))
+            .map(|(_, weight)| *weight)
+    };
+
+    // `identity` is acceptable unless excluded explicitly or through `*`, but
+    // only as a last resort when not listed
+    let identity = weight_of(&["identity"]).unwrap_or(1);
+    let best = most_preferred(Encoding::ALL.iter().filter_map(|encoding| {
+        let weight = match encoding {
+            Encoding::Gzip => weight_of(&["gzip", "x-gzip"]),
+            encoding => weight_of(&[encoding.to_str()]),
+        }?;
+        Some((*encoding, weight))
+    }));
+
+    match best {
+        _ if prefer_identity && identity > 0 => Ok(None),
+        Some((encoding, weight)) if weight >= identity => Ok(Some(encoding)),
+        _ if identity > 0 => Ok(None),
+        _ => Err(StatusCode::NotAcceptable),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// 64 MiB of zeros, compressed.
+    fn bomb(encoding: Encoding) -> Vec<u8> {
+        let mut encoder = Encoder::new(encoding, CompressionLevel::Fastest).unwrap();
+        let zeros = vec![0; 1024 * 1024];
+        let mut bomb = vec![];
+        for _ in 0..64 {
+            bomb.extend(encoder.write(&zeros).unwrap());
+        }
+        bomb.extend(encoder.finish().unwrap());
+        bomb
+    }
+
+    #[test]
+    fn decoders_stop_at_the_budget() {
+        for encoding in Encoding::ALL {
+            let bomb = bomb(encoding);
+            let mut decoder = Decoder::new(encoding).unwrap();
+            let mut decoded = 0;
+            for step in bomb.chunks(512) {
+                let output = decoder.write(step, 100_000).unwrap();
+                assert!(output.len() <= 100_000, "{:?}", encoding);
+                decoded += output.len();
+                if output.len() == 100_000 {
+                    break;
+                }
+            }
+            assert!(
+                decoded < 64 * 1024 * 1024,
+                "{:?} ignored the budget",
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn decoders_round_trip() {
+        let data = "round trip ".repeat(10_000);
+        for encoding in Encoding::ALL {
+            let encoded = encoding
+                .encode(data.as_bytes(), CompressionLevel::Default)
+                .unwrap();
+            let mut decoder = Decoder::new(encoding).unwrap();
+            let mut decoded = vec![];
+            for step in encoded.chunks(512) {
+                decoded.extend(decoder.write(step, usize::MAX).unwrap());
+            }
+            decoded.extend(decoder.finish().unwrap());
+            assert_eq!(decoded, data.as_bytes(), "{:?}", encoding);
+
+            // Cut short
+            let mut decoder = Decoder::new(encoding).unwrap();
+            decoder
+                .write(&encoded[..encoded.len() / 2], usize::MAX)
+                .unwrap();
+            assert!(decoder.finish().is_err(), "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn without_accept_encoding_sends_identity() {
+        assert_eq!(negotiate(None, false), Ok(None));
+        assert_eq!(negotiate(Some(""), false), Ok(None));
+    }
+
+    #[test]
+    fn picks_the_highest_weight() {
+        assert_eq!(negotiate(Some("gzip"), false), Ok(Some(Encoding::Gzip)));
+        assert_eq!(negotiate(Some("x-gzip"), false), Ok(Some(Encoding::Gzip)));
+        assert_eq!(
+            negotiate(Some("GZIP;Q=0.5"), false),
+            Ok(Some(Encoding::Gzip))
+        );
+        assert_eq!(
+            negotiate(Some("gzip;q=0.5, br;q=0.8, deflate;q=0.1"), false),
+            Ok(Some(Encoding::Brotli))
+        );
+        assert_eq!(
+            negotiate(Some("gzip;q=1, zstd;q=0.999"), false),
+            Ok(Some(Encoding::Gzip))
+        );
+        assert_eq!(negotiate(Some("compress, bzip2"), false), Ok(None));
+    }
+
+    #[test]
+    fn breaks_ties_by_server_preference() {
+        assert_eq!(
+            negotiate(Some("deflate, gzip, br, zstd"), false),
+            Ok(Some(Encoding::Zstd))
+        );
+        assert_eq!(
+            negotiate(Some("deflate, gzip"), false),
+            Ok(Some(Encoding::Gzip))
+        );
+        assert_eq!(negotiate(Some("*"), false), Ok(Some(Encoding::Zstd)));
+    }
+
+    #[test]
+    fn honours_q_zero() {
+        assert_eq!(negotiate(Some("gzip;q=0"), false), Ok(None));
+        assert_eq!(
+            negotiate(Some("*, zstd;q=0, br;q=0"), false),
+            Ok(Some(Encoding::Gzip))
+        );
+        assert_eq!(
+            negotiate(Some("*;q=0, deflate"), false),
+            Ok(Some(Encoding::Deflate))
+        );
+        // Malformed weights drop the element, not the whole field
+        assert_eq!(
+            negotiate(Some("br;q=2, gzip"), false),
+            Ok(Some(Encoding::Gzip))
+        );
+    }
+
+    #[test]
+    fn weighs_identity() {
+        assert_eq!(negotiate(Some("identity"), false), Ok(None));
+        assert_eq!(negotiate(Some("identity, gzip;q=0.5"), false), Ok(None));
+        assert_eq!(
+            negotiate(Some("identity;q=0.5, gzip"), false),
+            Ok(Some(Encoding::Gzip))
+        );
+        assert_eq!(
+            negotiate(Some("identity;q=0, gzip;q=0.1"), false),
+            Ok(Some(Encoding::Gzip))
+        );
+    }
+
+    #[test]
+    fn prefers_identity_unless_refused() {
+        assert_eq!(negotiate(Some("gzip, br"), true), Ok(None));
+        assert_eq!(negotiate(Some("identity;q=0.1, gzip"), true), Ok(None));
+        assert_eq!(
+            negotiate(Some("identity;q=0, gzip"), true),
+            Ok(Some(Encoding::Gzip))
+        );
+        assert_eq!(
+            negotiate(Some("*;q=0, br"), true),
+            Ok(Some(Encoding::Brotli))
+        );
+        assert_eq!(
+            negotiate(Some("identity;q=0"), true),
+            Err(StatusCode::NotAcceptable)
+        );
+    }
+
+    #[test]
+    fn fails_when_nothing_is_acceptable() {
+        assert_eq!(
+            negotiate(Some("identity;q=0"), false),
+            Err(StatusCode::NotAcceptable)
+        );
+        assert_eq!(
+            negotiate(Some("*;q=0"), false),
+            Err(StatusCode::NotAcceptable)
+        );
+        assert_eq!(
+            negotiate(Some("compress, *;q=0"), false),
+            Err(StatusCode::NotAcceptable)
+        );
+        assert_eq!(
+            negotiate(Some("gzip;q=0, identity;q=0"), false),
+            Err(StatusCode::NotAcceptable)
+        );
+    }
+}
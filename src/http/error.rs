@@ -0,0 +1,78 @@
+use thiserror::Error;
+
+use super::status_code::StatusCode;
+
+/// Reasons a request can't be read off the connection. The connection is
+/// answered with [`ParseError::status_code`] and closed.
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("line is longer than {0} bytes")]
+    LineTooLong(usize),
+    #[error("line isn't terminated by CRLF")]
+    InvalidLineEnding,
+    #[error("request line is longer than {0} bytes")]
+    RequestLineTooLong(usize),
+    #[error("invalid request line `{0}`")]
+    InvalidRequestLine(String),
+    #[error("unsupported method `{0}`")]
+    UnsupportedMethod(String),
+    #[error("unsupported HTTP version `{0}`")]
+    UnsupportedVersion(String),
+    #[error("header section is too large")]
+    HeadersTooLarge,
+    #[error("invalid header line `{0}`")]
+    InvalidHeader(String),
+    #[error("header `{0}` must not be repeated")]
+    DuplicateHeader(String),
+    #[error("missing host header")]
+    MissingHost,
+    #[error("message framing is ambiguous")]
+    ConflictingFraming,
+    #[error("invalid content-length `{0}`")]
+    InvalidContentLength(String),
+    #[error("malformed chunked body: {0}")]
+    MalformedChunk(&'static str),
+    #[error("invalid transfer-encoding `{0}`")]
+    InvalidTransferEncoding(String),
+    #[error("unsupported transfer-encoding `{0}`")]
+    UnsupportedTransferEncoding(String),
+    #[error("request body is larger than {0} bytes")]
+    BodyTooLarge(u64),
+    #[error("unsupported content-encoding `{0}`")]
+    UnsupportedContentEncoding(String),
+    #[error("request body isn't valid {0} data")]
+    InvalidEncodedBody(&'static str),
+    #[error("request body decompresses more than {0} times its size")]
+    CompressionRatioTooHigh(u64),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl ParseError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ParseError::LineTooLong(_)
+            | ParseError::InvalidLineEnding
+            | ParseError::InvalidRequestLine(_)
+            | ParseError::InvalidHeader(_)
+            | ParseError::DuplicateHeader(_)
+            | ParseError::MissingHost
+            | ParseError::ConflictingFraming
+            | ParseError::InvalidContentLength(_)
+            | ParseError::MalformedChunk(_)
+            | ParseError::InvalidTransferEncoding(_)
+            | ParseError::InvalidEncodedBody(_) => StatusCode::BadRequest,
+            ParseError::RequestLineTooLong(_) => StatusCode::UriTooLong,
+            ParseError::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            ParseError::UnsupportedMethod(_) | ParseError::UnsupportedTransferEncoding(_) => {
+                StatusCode::NotImplemented
+            }
+            ParseError::UnsupportedVersion(_) => StatusCode::HttpVersionNotSupported,
+            ParseError::BodyTooLarge(_) | ParseError::CompressionRatioTooHigh(_) => {
+                StatusCode::ContentTooLarge
+            }
+            ParseError::UnsupportedContentEncoding(_) => StatusCode::UnsupportedMediaType,
+            ParseError::Io(_) => StatusCode::BadRequest,
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::{File, Metadata},
+    io::Read,
+    str::FromStr,
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use super::{body::CHUNK_SIZE, encoding::Encoding};
+
+/// Most tags [`FILE_TAGS`] holds, it starts over once full.
+const MAX_FILE_TAGS: usize = 4096;
+
+/// Strong tags of the files hashed so far, by the version they were made for.
+static FILE_TAGS: Mutex<BTreeMap<FileVersion, ETag>> = Mutex::new(BTreeMap::new());
+
+/// An entity tag (RFC 9110 section 8.8.3), the validator clients send back
+/// in `If-None-Match`, `If-Match` and `If-Range`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ETag {
+    weak: bool,
+    tag: String,
+}
+
+impl ETag {
+    /// Strong tag for a body, changes whenever one of its bytes does.
+    #[allow(dead_code)]
+    pub fn from_content(content: &[u8]) -> Self {
+        let mut hasher = Fnv::default();
+        hasher.update(content);
+        hasher.etag()
+    }
+    /// Strong tag for everything `reader` returns, e.g. a whole file.
+    pub fn from_reader(mut reader: impl Read) -> std::io::Result<Self> {
+        let mut hasher = Fnv::default();
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(hasher.etag()),
+                n => hasher.update(&buf[..n]),
+            }
+        }
+    }
+    /// Strong tag for the content of `file`, see [`ETag::from_reader`].
+    /// Hashing runs on the blocking thread pool, and only once per version of
+    /// the file: the tag is reused until its metadata changes.
+    pub async fn from_file(file: &File) -> std::io::Result<Self> {
+        let version = FileVersion::of(&file.metadata()?);
+        if let Some(etag) = version.and_then(|version| file_tags().get(&version).cloned()) {
+            return Ok(etag);
+        }
+        let reader = file.try_clone()?;
+        let etag = tokio::task::spawn_blocking(move || ETag::from_reader(&reader))
+            .await
+            .map_err(std::io::Error::other)??;
+        // Not kept if the file changed while it was read
+        let metadata = file.metadata()?;
+        if version.is_some() && version == FileVersion::of(&metadata) {
+            Self::remember(&metadata, &etag);
+        }
+        Ok(etag)
+    }
+    /// Records `etag` as the tag of the file `metadata` describes, e.g. one
+    /// hashed with [`Fnv`] as it was written.
+    pub fn remember(metadata: &Metadata, etag: &ETag) {
+        let Some(version) = FileVersion::of(metadata) else {
+            return;
+        };
+        let mut tags = file_tags();
+        if tags.len() >= MAX_FILE_TAGS {
+            tags.clear();
+        }
+        tags.insert(version, etag.clone());
+    }
+    /// Weak tag for a file from its size and modification time. Cheap, but
+    /// two versions written within the clock's resolution with the same size
+    /// look alike, hence weak.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+        ETag {
+            weak: true,
+            tag: format!("{:x}-{:x}", metadata.len(), modified),
+        }
+    }
+    /// `None` if `tag` has characters an entity tag can't hold.
+    #[allow(dead_code)]
+    pub fn strong(tag: &str) -> Option<Self> {
+        tag.bytes().all(is_etagc).then(|| ETag {
+            weak: false,
+            tag: tag.to_string(),
+        })
+    }
+    #[allow(dead_code)]
+    pub fn weak(tag: &str) -> Option<Self> {
+        Self::strong(tag).map(ETag::into_weak)
+    }
+    #[allow(dead_code)]
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+    pub fn into_weak(mut self) -> Self {
+        self.weak = true;
+        self
+    }
+    /// Tag of the representation encoded with `encoding`, which isn't byte
+    /// for byte what this tag was made for but is just as stable: the codecs
+    /// give the same output for the same input and level.
+    pub fn encoded(&self, encoding: Encoding) -> Self {
+        ETag {
+            weak: self.weak,
+            tag: format!("{}-{}", self.tag, encoding.to_str()),
+        }
+    }
+
+    /// Both strong and identical, required by `If-Match` and `If-Range`
+    /// (RFC 9110 section 8.8.3.2).
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+    /// Identical, weak or not, enough for `If-None-Match`.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+
+    /// Parses the list of `If-Match`/`If-None-Match`, `None` if malformed.
+    /// `*` isn't an entity tag and has to be checked for by the caller.
+    pub fn parse_list(list: &str) -> Option<Vec<ETag>> {
+        let mut etags = vec![];
+        let mut rest = list;
+        loop {
+            rest = rest.trim_start_matches([' ', '\t', ',']);
+            if rest.is_empty() {
+                return Some(etags);
+            }
+            let weak = rest.starts_with("W/");
+            let quoted = rest.strip_prefix("W/").unwrap_or(rest).strip_prefix('"')?;
+            // Entity tags can contain commas but no quotes
+            let end = quoted.find('"')?;
+            etags.push(ETag {
+                weak,
+                tag: quoted[..end].to_string(),
+            });
+            rest = quoted[end + 1..].trim_start_matches([' ', '\t']);
+            if !rest.is_empty() && !rest.starts_with(',') {
+                return None;
+            }
+        }
+    }
+}
+
+fn file_tags() -> std::sync::MutexGuard<'static, BTreeMap<FileVersion, ETag>> {
+    FILE_TAGS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Tells versions of a file apart without reading it. Unlike the
+/// modification time, the change time can't be set back and moves on every
+/// write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct FileVersion {
+    device: u64,
+    inode: u64,
+    len: u64,
+    changed: (i64, i64),
+    modified: (i64, i64),
+}
+
+impl FileVersion {
+    #[cfg(unix)]
+    fn of(metadata: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        Some(FileVersion {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+            len: metadata.len(),
+            changed: (metadata.ctime(), metadata.ctime_nsec()),
+            modified: (metadata.mtime(), metadata.mtime_nsec()),
+        })
+    }
+    /// Without inode numbers two files can't be told apart, nothing is
+    /// cached.
+    #[cfg(not(unix))]
+    fn of(_metadata: &Metadata) -> Option<Self> {
+        None
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds unlike `DefaultHasher`.
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv {
+    pub fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.0 = (self.0 ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    pub fn etag(&self) -> ETag {
+        ETag {
+            weak: false,
+            tag: format!("{:016x}", self.0),
+        }
+    }
+}
+
+/// `etagc` of RFC 9110 section 8.8.3: visible ASCII but `"`, and obs-text.
+fn is_etagc(b: u8) -> bool {
+    b == 0x21 || (0x23..=0x7e).contains(&b) || b >= 0x80
+}
+
+impl Display for ETag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.weak {
+            write!(f, "W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+impl FromStr for ETag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let weak = s.starts_with("W/");
+        let tag = s
+            .strip_prefix("W/")
+            .unwrap_or(s)
+            .strip_prefix('"')
+            .and_then(|tag| tag.strip_suffix('"'))
+            .filter(|tag| tag.bytes().all(is_etagc))
+            .ok_or_else(|| format!("Invalid entity tag: {}", s))?;
+        Ok(ETag {
+            weak,
+            tag: tag.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn strong(tag: &str) -> ETag {
+        ETag::strong(tag).unwrap()
+    }
+
+    fn weak(tag: &str) -> ETag {
+        ETag::weak(tag).unwrap()
+    }
+
+    #[test]
+    fn parses_lists() {
+        assert_eq!(ETag::parse_list(""), Some(vec![]));
+        assert_eq!(ETag::parse_list(r#""abc""#), Some(vec![strong("abc")]));
+        assert_eq!(
+            ETag::parse_list(r#" "a" ,W/"b",, "" , W/"c,d""#),
+            Some(vec![strong("a"), weak("b"), strong(""), weak("c,d")])
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lists() {
+        for list in [
+            "abc",
+            r#""abc"#,
+            r#""a" "b""#,
+            r#"w/"a""#,
+            r#"W/ "a""#,
+            r#""a"x"#,
+            "*",
+        ] {
+            assert_eq!(ETag::parse_list(list), None, "{}", list);
+        }
+    }
+
+    #[test]
+    fn parses_and_formats_single_tags() {
+        assert_eq!(r#""abc""#.parse(), Ok(strong("abc")));
+        assert_eq!(r#" W/"abc" "#.parse(), Ok(weak("abc")));
+        assert!(r#""a"b""#.parse::<ETag>().is_err());
+        assert!("abc".parse::<ETag>().is_err());
+        assert_eq!(strong("abc").to_string(), r#""abc""#);
+        assert_eq!(weak("abc").to_string(), r#"W/"abc""#);
+        assert_eq!(ETag::strong("a b"), None);
+    }
+
+    #[test]
+    fn compares_strongly_and_weakly() {
+        assert!(strong("a").strong_eq(&strong("a")));
+        assert!(!strong("a").strong_eq(&weak("a")));
+        assert!(!weak("a").strong_eq(&weak("a")));
+        assert!(!strong("a").strong_eq(&strong("b")));
+        assert!(weak("a").weak_eq(&strong("a")));
+        assert!(!weak("a").weak_eq(&weak("b")));
+    }
+
+    #[test]
+    fn tags_each_coding() {
+        assert_eq!(strong("abc").encoded(Encoding::Gzip), strong("abc-gzip"));
+        assert_eq!(weak("abc").encoded(Encoding::Brotli), weak("abc-br"));
+    }
+
+    #[tokio::test]
+    async fn tags_files_once_per_version() {
+        let path = std::env::temp_dir().join(format!("etag-{}", std::process::id()));
+        std::fs::write(&path, "first").unwrap();
+        let file = File::open(&path).unwrap();
+        assert_eq!(
+            ETag::from_file(&file).await.unwrap(),
+            ETag::from_content(b"first")
+        );
+        // A remembered tag is used as long as the file is unchanged
+        ETag::remember(&file.metadata().unwrap(), &strong("remembered"));
+        assert_eq!(ETag::from_file(&file).await.unwrap(), strong("remembered"));
+
+        std::fs::write(&path, "second").unwrap();
+        let file = File::open(&path).unwrap();
+        assert_eq!(
+            ETag::from_file(&file).await.unwrap(),
+            ETag::from_content(b"second")
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hashes_content() {
+        assert_eq!(ETag::from_content(b""), strong("cbf29ce484222325"));
+        assert_eq!(
+            ETag::from_reader(&b"hello"[..]).unwrap(),
+            ETag::from_content(b"hello")
+        );
+        assert_ne!(ETag::from_content(b"hello"), ETag::from_content(b"hellp"));
+    }
+}
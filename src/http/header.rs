@@ -7,6 +7,25 @@ pub enum Header {
     ContentEncoding,
     UserAgent,
     Host,
+    Location,
+    TransferEncoding,
+    Trailer,
+    Connection,
+    Expect,
+    Date,
+    Server,
+    Vary,
+    Accept,
+    Range,
+    ContentRange,
+    AcceptRanges,
+    IfRange,
+    ETag,
+    LastModified,
+    IfNoneMatch,
+    IfModifiedSince,
+    IfMatch,
+    IfUnmodifiedSince,
 }
 
 impl Header {
@@ -18,6 +37,25 @@ impl Header {
             Header::ContentEncoding => "content-encoding",
             Header::UserAgent => "user-agent",
             Header::Host => "host",
+            Header::Location => "location",
+            Header::TransferEncoding => "transfer-encoding",
+            Header::Trailer => "trailer",
+            Header::Connection => "connection",
+            Header::Expect => "expect",
+            Header::Date => "date",
+            Header::Server => "server",
+            Header::Vary => "vary",
+            Header::Accept => "accept",
+            Header::Range => "range",
+            Header::ContentRange => "content-range",
+            Header::AcceptRanges => "accept-ranges",
+            Header::IfRange => "if-range",
+            Header::ETag => "etag",
+            Header::LastModified => "last-modified",
+            Header::IfNoneMatch => "if-none-match",
+            Header::IfModifiedSince => "if-modified-since",
+            Header::IfMatch => "if-match",
+            Header::IfUnmodifiedSince => "if-unmodified-since",
         }
     }
 }
@@ -33,6 +71,25 @@ impl FromStr for Header {
             "content-encoding" => Ok(Header::ContentEncoding),
             "user-agent" => Ok(Header::UserAgent),
             "host" => Ok(Header::Host),
+            "location" => Ok(Header::Location),
+            "transfer-encoding" => Ok(Header::TransferEncoding),
+            "trailer" => Ok(Header::Trailer),
+            "connection" => Ok(Header::Connection),
+            "expect" => Ok(Header::Expect),
+            "date" => Ok(Header::Date),
+            "server" => Ok(Header::Server),
+            "vary" => Ok(Header::Vary),
+            "accept" => Ok(Header::Accept),
+            "range" => Ok(Header::Range),
+            "content-range" => Ok(Header::ContentRange),
+            "accept-ranges" => Ok(Header::AcceptRanges),
+            "if-range" => Ok(Header::IfRange),
+            "etag" => Ok(Header::ETag),
+            "last-modified" => Ok(Header::LastModified),
+            "if-none-match" => Ok(Header::IfNoneMatch),
+            "if-modified-since" => Ok(Header::IfModifiedSince),
+            "if-match" => Ok(Header::IfMatch),
+            "if-unmodified-since" => Ok(Header::IfUnmodifiedSince),
             _ => Err("Unsupported Header".to_string()),
         }
     }
@@ -0,0 +1,262 @@
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    #[error("invalid header name `{0}`")]
+    InvalidName(String),
+    #[error("invalid value for header `{0}`")]
+    InvalidValue(String),
+}
+
+/// Header fields in the order they were added. Names keep the casing they
+/// were given, lookups ignore it. A name can be present more than once
+/// (`Set-Cookie`, several `Accept` lines...).
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// First value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of `name`, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values of `name` combined into one comma separated list, which is
+    /// how repeated list-based fields like `Transfer-Encoding` have to be
+    /// read (RFC 9110 section 5.3).
+    pub fn get_joined(&self, name: &str) -> Option<String> {
+        let values = self.get_all(name).collect::<Vec<_>>();
+        if values.is_empty() {
+            return None;
+        }
+        Some(values.join(", "))
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets `name` to `value`, replacing every existing value of it.
+    pub fn insert(&mut self, name: &str, value: &str) -> Result<(), HeaderError> {
+        validate(name, value)?;
+        self.remove(name);
+        self.entries.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Adds `value` to `name`, keeping existing values.
+    pub fn append(&mut self, name: &str, value: &str) -> Result<(), HeaderError> {
+        validate(name, value)?;
+        self.entries.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Removes every value of `name`, returns whether there was any.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        len != self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+fn validate(name: &str, value: &str) -> Result<(), HeaderError> {
+    if name.is_empty() || !name.bytes().all(is_tchar) {
+        return Err(HeaderError::InvalidName(name.to_string()));
+    }
+    // field-value can't carry CR, LF or NUL, and surrounding whitespace isn't
+    // part of it (RFC 9110 section 5.5)
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t')
+        || value.starts_with([' ', '\t'])
+        || value.ends_with([' ', '\t'])
+    {
+        return Err(HeaderError::InvalidValue(name.to_string()));
+    }
+    Ok(())
+}
+
+/// `tchar` from RFC 9110 section 5.6.2, the characters allowed in a token
+/// such as a field name.
+pub fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// `qvalue = ( "0" [ "." 0*3DIGIT ] ) / ( "1" [ "." 0*3("0") ] )`, in
+/// thousandths.
+pub fn parse_qvalue(value: &str) -> Option<u16> {
+    let (int, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let fraction = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+    match int {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Of `candidates` and their weight, the one with the highest weight, the
+/// earliest on ties. Those weighted 0 are not acceptable, `None` if that
+/// leaves none.
+pub fn most_preferred<T>(candidates: impl IntoIterator<Item = (T, u16)>) -> Option<(T, u16)> {
+    candidates
+        .into_iter()
+        .filter(|(_, weight)| *weight > 0)
+        .fold(None, |best, (candidate, weight)| match best {
+            Some((_, best_weight)) if best_weight >= weight => best,
+            _ => Some((candidate, weight)),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn headers(entries: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.append(name, value).unwrap();
+        }
+        headers
+    }
+
+    #[test]
+    fn looks_names_up_case_insensitively() {
+        let headers = headers(&[("Content-Type", "text/plain"), ("X-Token", "a")]);
+        assert_eq!(headers.get("content-type"), Some("text/plain"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/plain"));
+        assert!(headers.contains_key("x-token"));
+        assert_eq!(headers.get("Content-Length"), None);
+        // Names keep their casing
+        assert_eq!(
+            headers.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            ["Content-Type", "X-Token"]
+        );
+    }
+
+    #[test]
+    fn keeps_repeated_values_in_order() {
+        let headers = headers(&[
+            ("Set-Cookie", "a=1"),
+            ("Vary", "accept"),
+            ("set-cookie", "b=2"),
+            ("SET-COOKIE", "c=3"),
+        ]);
+        assert_eq!(headers.get("Set-Cookie"), Some("a=1"));
+        assert_eq!(
+            headers.get_all("set-cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2", "c=3"]
+        );
+        assert_eq!(
+            headers.get_joined("Set-Cookie").as_deref(),
+            Some("a=1, b=2, c=3")
+        );
+        assert_eq!(headers.get_joined("vary").as_deref(), Some("accept"));
+        assert_eq!(headers.get_joined("Accept"), None);
+        assert_eq!(headers.len(), 4);
+    }
+
+    #[test]
+    fn insert_replaces_every_value() {
+        let mut headers = headers(&[("Vary", "accept"), ("ETag", "\"a\""), ("vary", "origin")]);
+        headers.append("Vary", "cookie").unwrap();
+        assert_eq!(headers.get_all("vary").count(), 3);
+        headers.insert("VARY", "accept-encoding").unwrap();
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [("ETag", "\"a\""), ("VARY", "accept-encoding")]
+        );
+        assert!(headers.remove("vary"));
+        assert!(!headers.remove("vary"));
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_names_and_values() {
+        let mut headers = HeaderMap::new();
+        for name in ["", "X Token", "X-Token:", "X-Tök", "(X)"] {
+            assert_eq!(
+                headers.append(name, "a"),
+                Err(HeaderError::InvalidName(name.to_string()))
+            );
+        }
+        for value in ["a\r\nX-Injected: 1", "a\nb", "a\rb", "a\0b", " a", "a\t"] {
+            assert_eq!(
+                headers.insert("X-Token", value),
+                Err(HeaderError::InvalidValue("X-Token".to_string())),
+                "{:?}",
+                value
+            );
+        }
+        assert!(headers.is_empty());
+        // Inner whitespace, tabs and obs-text are fine
+        headers.append("X-Token", "a b\tc é").unwrap();
+        headers.append("X-Empty", "").unwrap();
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn parses_qvalues() {
+        for (value, expected) in [
+            ("1", Some(1000)),
+            ("1.000", Some(1000)),
+            ("0", Some(0)),
+            ("0.5", Some(500)),
+            ("0.05", Some(50)),
+            ("0.001", Some(1)),
+            ("1.001", None),
+            ("0.0001", None),
+            ("2", None),
+            (".5", None),
+            ("0.-1", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_qvalue(value), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn prefers_the_heaviest_then_the_earliest() {
+        assert_eq!(
+            most_preferred([("a", 500), ("b", 1000), ("c", 1000)]),
+            Some(("b", 1000))
+        );
+        assert_eq!(most_preferred([("a", 0), ("b", 1)]), Some(("b", 1)));
+        assert_eq!(most_preferred([("a", 0)]), None);
+        assert_eq!(most_preferred(Vec::<(&str, u16)>::new()), None);
+    }
+}
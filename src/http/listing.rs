@@ -0,0 +1,253 @@
+use std::{fs, path::Path, time::SystemTime};
+
+use super::{date, path::percent_encode};
+
+/// One file or directory shown in a listing.
+#[derive(Debug)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    /// `None` for directories.
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+}
+
+/// One page of a directory's entries, directories first, then by name.
+#[derive(Debug)]
+pub struct Listing {
+    /// Request path of the directory, ending with `/`.
+    pub path: String,
+    /// Whether a `../` link makes sense, i.e. this isn't the mount root.
+    pub has_parent: bool,
+    pub page: usize,
+    pub pages: usize,
+    pub total: usize,
+    pub entries: Vec<Entry>,
+}
+
+/// Reads the entries of `dir` that `keep` lets through, sorted. Entries that
+/// can't be read are skipped.
+pub fn read_entries<F>(dir: &Path, mut keep: F) -> std::io::Result<Vec<Entry>>
+where
+    F: FnMut(&str) -> bool,
+{
+    let mut entries = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            if !keep(&name) {
+                return None;
+            }
+            // Follows symlinks, `keep` already decided they can be shown
+            let metadata = fs::metadata(entry.path()).ok()?;
+            Some(Entry {
+                name,
+                is_dir: metadata.is_dir(),
+                size: (!metadata.is_dir()).then_some(metadata.len()),
+                modified: metadata.modified().ok(),
+            })
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+impl Listing {
+    pub fn to_html(&self) -> String {
+        let title = format!("Index of {}", escape_html(&self.path));
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n",
+            title, title
+        );
+        if self.has_parent {
+            html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+        }
+        for entry in &self.entries {
+            let suffix = if entry.is_dir { "/" } else { "" };
+            html.push_str(&format!(
+                "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                percent_encode(&entry.name),
+                suffix,
+                escape_html(&entry.name),
+                suffix,
+                entry
+                    .size
+                    .map(|size| size.to_string())
+                    .unwrap_or("-".to_string()),
+                entry.modified.map(date::format).unwrap_or_default()
+            ));
+        }
+        html.push_str("</table>\n");
+        if self.pages > 1 {
+            html.push_str("<p>");
+            if self.page > 1 {
+                html.push_str(&format!(
+                    "<a href=\"?page={}\">Previous</a> ",
+                    self.page - 1
+                ));
+            }
+            html.push_str(&format!("Page {} of {}", self.page, self.pages));
+            if self.page < self.pages {
+                html.push_str(&format!(" <a href=\"?page={}\">Next</a>", self.page + 1));
+            }
+            html.push_str("</p>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    pub fn to_json(&self) -> String {
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"name\":{},\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+                    json_string(&entry.name),
+                    if entry.is_dir { "directory" } else { "file" },
+                    entry
+                        .size
+                        .map(|size| size.to_string())
+                        .unwrap_or("null".to_string()),
+                    entry
+                        .modified
+                        .map(|modified| json_string(&date::format(modified)))
+                        .unwrap_or("null".to_string())
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"path\":{},\"page\":{},\"pages\":{},\"total\":{},\"entries\":[{}]}}",
+            json_string(&self.path),
+            self.page,
+            self.pages,
+            self.total,
+            entries.join(",")
+        )
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::http::temp_dir::TempDir;
+
+    fn file(name: &str) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir: false,
+            size: Some(3),
+            modified: None,
+        }
+    }
+
+    fn listing(entries: Vec<Entry>) -> Listing {
+        Listing {
+            path: "/files/".to_string(),
+            has_parent: false,
+            page: 1,
+            pages: 1,
+            total: entries.len(),
+            entries,
+        }
+    }
+
+    #[test]
+    fn sorts_directories_first_then_by_name() {
+        let tmp = TempDir::new("listing-sort");
+        for dir in ["b_dir", "a_dir", "skipped"] {
+            fs::create_dir(tmp.0.join(dir)).unwrap();
+        }
+        for name in ["c.txt", "B.txt", "a.txt"] {
+            fs::write(tmp.0.join(name), "abc").unwrap();
+        }
+        let entries = read_entries(&tmp.0, |name| name != "skipped").unwrap();
+        let names = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                ("a_dir", None),
+                ("b_dir", None),
+                ("B.txt", Some(3)),
+                ("a.txt", Some(3)),
+                ("c.txt", Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_names_in_html() {
+        let html = listing(vec![
+            file("<script>alert(1)</script>"),
+            file("\"quoted\" & 'single'"),
+        ])
+        .to_html();
+        assert!(!html.contains("<script>"), "{}", html);
+        assert!(
+            html.contains(">&lt;script&gt;alert(1)&lt;/script&gt;</a>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains(">&quot;quoted&quot; &amp; &#39;single&#39;</a>"),
+            "{}",
+            html
+        );
+        // Links are percent-encoded, so they can't close the attribute
+        assert!(!html.contains("href=\"\"quoted"), "{}", html);
+    }
+
+    #[test]
+    fn escapes_names_in_json() {
+        let json = listing(vec![file("say \"hi\"\\\n\u{1}.txt")]).to_json();
+        assert_eq!(
+            json,
+            "{\"path\":\"/files/\",\"page\":1,\"pages\":1,\"total\":1,\"entries\":[\
+             {\"name\":\"say \\\"hi\\\"\\\\\\n\\u0001.txt\",\"type\":\"file\",\"size\":3,\"modified\":null}]}"
+        );
+    }
+
+    #[test]
+    fn links_to_other_pages() {
+        let mut listing = listing(vec![file("a.txt")]);
+        listing.pages = 3;
+        listing.page = 2;
+        let html = listing.to_html();
+        assert!(html
+            .contains("<a href=\"?page=1\">Previous</a> Page 2 of 3 <a href=\"?page=3\">Next</a>"));
+        listing.page = 1;
+        assert!(!listing.to_html().contains("Previous"));
+    }
+}
@@ -3,6 +3,8 @@ use std::str::FromStr;
 #[derive(Debug)]
 pub enum Method {
     Get,
+    /// A `GET` without the body, answered by the `GET` routes.
+    Head,
     Post,
     Put,
     Delete,
@@ -13,9 +15,10 @@ impl FromStr for Method {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
             "POST" => Ok(Method::Post),
             "PUT" => Ok(Method::Put),
-            "Delete" => Ok(Method::Delete),
+            "DELETE" => Ok(Method::Delete),
             _ => Err(()),
         }
     }
@@ -1,19 +1,38 @@
+use error::ParseError;
 use tokio::io::{AsyncBufRead, AsyncRead};
 
-pub mod encoding;
+pub mod body;
+pub mod conditional;
 pub mod content_type;
+pub mod date;
+pub mod encoding;
+pub mod error;
+pub mod etag;
 pub mod header;
+pub mod header_map;
+pub mod listing;
 pub mod method;
+pub mod path;
+pub mod range;
 pub mod request;
+pub mod request_body;
 pub mod response;
+pub mod safe_path;
+#[cfg(target_os = "linux")]
+pub mod sendfile;
 pub mod server;
+pub mod static_files;
 pub mod status_code;
+#[cfg(test)]
+pub mod temp_dir;
+pub mod uploads;
+pub mod version;
 
 pub trait Parse<R>
 where
     R: AsyncRead + AsyncBufRead + Unpin,
 {
-    async fn parse(reader: &mut R) -> Self
+    async fn parse(reader: &mut R) -> Result<Self, ParseError>
     where
         Self: Sized;
 }
@@ -0,0 +1,268 @@
+use thiserror::Error;
+
+/// What to do with a `/` at the end of a request path.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// Leave the path as the client sent it.
+    Keep,
+    #[allow(dead_code)]
+    /// `/files/` becomes `/files`. The root path `/` is left alone.
+    Strip,
+    #[allow(dead_code)]
+    /// `/files` becomes `/files/`.
+    Append,
+}
+
+#[derive(Clone, Debug)]
+pub struct PathOptions {
+    /// Collapse runs of `/` into a single `/` (`/echo//abc` -> `/echo/abc`).
+    pub merge_slashes: bool,
+    pub trailing_slash: TrailingSlash,
+    /// Answer with `308 Permanent Redirect` to the canonical path instead of
+    /// routing a non-canonical one.
+    pub redirect: bool,
+}
+
+impl Default for PathOptions {
+    fn default() -> Self {
+        PathOptions {
+            merge_slashes: true,
+            trailing_slash: TrailingSlash::Keep,
+            redirect: false,
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PathError {
+    #[error("request target must start with `/`")]
+    NotOriginForm,
+    #[error("invalid percent-encoding in `{0}`")]
+    InvalidPercentEncoding(String),
+    #[error("percent-decoded value is not valid UTF-8")]
+    InvalidUtf8,
+}
+
+/// Normalises an origin-form request path (without the query string) as
+/// described in RFC 3986 section 6.2.2:
+/// - percent-encoded unreserved characters are decoded, every other triplet
+///   gets upper-case hex digits. Reserved characters like `%2F` stay encoded so
+///   they can't be confused with segment separators.
+/// - `.` and `..` segments are removed (section 5.2.4).
+/// - duplicate and trailing slashes are handled according to `options`.
+pub fn normalize(path: &str, options: &PathOptions) -> Result<String, PathError> {
+    if !path.starts_with('/') {
+        return Err(PathError::NotOriginForm);
+    }
+    let path = normalize_percent_encoding(path)?;
+    let path = if options.merge_slashes {
+        merge_slashes(&path)
+    } else {
+        path
+    };
+    let mut path = remove_dot_segments(&path);
+    match options.trailing_slash {
+        TrailingSlash::Keep => {}
+        TrailingSlash::Strip => {
+            while path.len() > 1 && path.ends_with('/') {
+                path.pop();
+            }
+        }
+        TrailingSlash::Append => {
+            if !path.ends_with('/') {
+                path.push('/');
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Fully decodes a percent-encoded value. Used for route parameters, after
+/// the path was matched, so `%2F` ends up as `/` in the parameter only.
+pub fn percent_decode(value: &str) -> Result<String, PathError> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = decode_triplet(bytes, i)
+                .ok_or_else(|| PathError::InvalidPercentEncoding(value.to_string()))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| PathError::InvalidUtf8)
+}
+
+/// Percent-encodes everything but unreserved characters, so `value` can be
+/// used as a single path segment or query value.
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match is_unreserved(byte) {
+            true => (byte as char).to_string(),
+            false => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn normalize_percent_encoding(path: &str) -> Result<String, PathError> {
+    let bytes = path.as_bytes();
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            normalized.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let byte = decode_triplet(bytes, i)
+            .ok_or_else(|| PathError::InvalidPercentEncoding(path.to_string()))?;
+        if is_unreserved(byte) {
+            normalized.push(byte);
+        } else {
+            normalized.extend(format!("%{:02X}", byte).bytes());
+        }
+        i += 3;
+    }
+    // Only ASCII was replaced with ASCII, so the input's UTF-8 is untouched.
+    String::from_utf8(normalized).map_err(|_| PathError::InvalidUtf8)
+}
+
+fn decode_triplet(bytes: &[u8], at: usize) -> Option<u8> {
+    let hex = bytes.get(at + 1..at + 3)?;
+    if !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c == '/' && merged.ends_with('/') {
+            continue;
+        }
+        merged.push(c);
+    }
+    merged
+}
+
+/// `remove_dot_segments` from RFC 3986 section 5.2.4, working on segments
+/// rather than the input buffer. `path` always starts with `/`.
+fn remove_dot_segments(path: &str) -> String {
+    let mut output: Vec<&str> = vec![];
+    let segments = path[1..].split('/').collect::<Vec<&str>>();
+    let last = segments.len() - 1;
+    for (i, segment) in segments.into_iter().enumerate() {
+        match segment {
+            "." | ".." => {
+                if segment == ".." {
+                    output.pop();
+                }
+                // `/a/b/..` resolves to the directory `/a/`, keep the slash
+                if i == last {
+                    output.push("");
+                }
+            }
+            _ => output.push(segment),
+        }
+    }
+    format!("/{}", output.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn normalized(path: &str, trailing_slash: TrailingSlash) -> String {
+        let options = PathOptions {
+            trailing_slash,
+            ..PathOptions::default()
+        };
+        normalize(path, &options).expect("path should normalize")
+    }
+
+    #[test]
+    fn removes_dot_segments() {
+        for (path, expected) in [
+            ("/", "/"),
+            ("/a/./b", "/a/b"),
+            ("/a/b/../c", "/a/c"),
+            ("/a/b/..", "/a/"),
+            ("/a/b/.", "/a/b/"),
+            ("/../../a", "/a"),
+            ("/a/%2e%2e/b", "/b"),
+            ("/a/%2E/b", "/a/b"),
+            ("/a/..b/c", "/a/..b/c"),
+        ] {
+            assert_eq!(
+                normalized(path, TrailingSlash::Keep),
+                expected,
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn normalizes_percent_encoding() {
+        // Unreserved characters are decoded, the rest is upper-cased
+        assert_eq!(normalized("/%7euser/%61", TrailingSlash::Keep), "/~user/a");
+        assert_eq!(normalized("/a%2fb/%3a", TrailingSlash::Keep), "/a%2Fb/%3A");
+        // An encoded `/` doesn't become a segment separator for `..`
+        assert_eq!(normalized("/a/..%2Fb", TrailingSlash::Keep), "/a/..%2Fb");
+        let options = PathOptions::default();
+        assert_eq!(
+            normalize("/a%zz", &options),
+            Err(PathError::InvalidPercentEncoding("/a%zz".to_string()))
+        );
+        assert_eq!(normalize("a/b", &options), Err(PathError::NotOriginForm));
+    }
+
+    #[test]
+    fn merges_slashes_only_when_enabled() {
+        assert_eq!(normalized("//echo///abc", TrailingSlash::Keep), "/echo/abc");
+        let options = PathOptions {
+            merge_slashes: false,
+            ..PathOptions::default()
+        };
+        assert_eq!(normalize("/echo//abc", &options).unwrap(), "/echo//abc");
+    }
+
+    #[test]
+    fn applies_trailing_slash_mode() {
+        for (path, keep, strip, append) in [
+            ("/", "/", "/", "/"),
+            ("/files", "/files", "/files", "/files/"),
+            ("/files/", "/files/", "/files", "/files/"),
+            ("/files//", "/files/", "/files", "/files/"),
+        ] {
+            assert_eq!(normalized(path, TrailingSlash::Keep), keep, "{:?}", path);
+            assert_eq!(normalized(path, TrailingSlash::Strip), strip, "{:?}", path);
+            assert_eq!(
+                normalized(path, TrailingSlash::Append),
+                append,
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn percent_decodes_route_params() {
+        assert_eq!(percent_decode("a%2Fb%20c").unwrap(), "a/b c");
+        assert_eq!(percent_decode("%ff"), Err(PathError::InvalidUtf8));
+        assert!(percent_decode("%4").is_err());
+        assert_eq!(percent_encode("a b/ü~"), "a%20b%2F%C3%BC~");
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    io::SeekFrom,
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::error;
+
+use crate::HTTP_LINE_ENDING;
+
+use super::{body::Body, body::CHUNK_SIZE, content_type::ContentType, header::Header};
+
+/// More ranges than this in one request are ignored and the whole
+/// representation is sent, a common way to abuse `Range`.
+const MAX_RANGES: usize = 32;
+
+/// What to do with a `Range` header, given the size of the representation.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRanges {
+    /// Malformed, another unit, or too many ranges: send everything.
+    Ignore,
+    /// Ranges to send, sorted with overlapping ones merged.
+    Satisfiable(Vec<RangeInclusive<u64>>),
+    /// None of the ranges overlaps the representation.
+    Unsatisfiable,
+}
+
+/// Parses `Range: bytes=0-499, -500, 1000-` (RFC 9110 section 14.1.2)
+/// against a representation of `len` bytes.
+pub fn parse(range: &str, len: u64) -> ByteRanges {
+    let Some((unit, ranges)) = range.split_once('=') else {
+        return ByteRanges::Ignore;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return ByteRanges::Ignore;
+    }
+    let specs = ranges
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect::<Vec<_>>();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return ByteRanges::Ignore;
+    }
+    let mut satisfiable = vec![];
+    for spec in specs {
+        let Some((first, last)) = spec.split_once('-') else {
+            return ByteRanges::Ignore;
+        };
+        let is_number =
+            |value: &str| !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit());
+        let range = match (first, last) {
+            // Suffix range, the last `last` bytes
+            ("", suffix) if is_number(suffix) => {
+                let Ok(suffix) = suffix.parse::<u64>() else {
+                    return ByteRanges::Ignore;
+                };
+                (suffix > 0 && len > 0).then(|| len.saturating_sub(suffix)..=len - 1)
+            }
+            (first, last) if is_number(first) && (last.is_empty() || is_number(last)) => {
+                let Ok(first) = first.parse::<u64>() else {
+                    return ByteRanges::Ignore;
+                };
+                let last = match last {
+                    "" => u64::MAX,
+                    last => match last.parse::<u64>() {
+                        Ok(last) if last >= first => last,
+                        Ok(_) => return ByteRanges::Ignore,
+                        Err(_) => u64::MAX,
+                    },
+                };
+                (first < len).then(|| first..=last.min(len - 1))
+            }
+            _ => return ByteRanges::Ignore,
+        };
+        satisfiable.extend(range);
+    }
+    if satisfiable.is_empty() {
+        return ByteRanges::Unsatisfiable;
+    }
+    satisfiable.sort_by_key(|range| *range.start());
+    let mut merged: Vec<RangeInclusive<u64>> = vec![];
+    for range in satisfiable {
+        match merged.last_mut() {
+            // Overlapping or adjacent
+            Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                *last = *last.start()..=*last.end().max(range.end());
+            }
+            _ => merged.push(range),
+        }
+    }
+    ByteRanges::Satisfiable(merged)
+}
+
+/// `Content-Range` value for `range` of a `len` bytes representation.
+pub fn content_range(range: &RangeInclusive<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start(), range.end(), len)
+}
+
+/// `Content-Range` value of a `416 Range Not Satisfiable` response.
+pub fn unsatisfied_range(len: u64) -> String {
+    format!("bytes */{}", len)
+}
+
+/// A `multipart/byteranges` body with one part per range of `file`. Returns
+/// the body and its content type, which carries the boundary.
+pub fn multipart(
+    file: File,
+    ranges: Vec<RangeInclusive<u64>>,
+    len: u64,
+    content_type: &ContentType,
+) -> (Body, ContentType) {
+    let boundary = boundary();
+    let part_heads = ranges
+        .iter()
+        .map(|range| {
+            format!(
+                "--{}{}{}: {}{}{}: {}{}{}",
+                boundary,
+                HTTP_LINE_ENDING,
+                Header::ContentType.to_str(),
+                content_type,
+                HTTP_LINE_ENDING,
+                Header::ContentRange.to_str(),
+                content_range(range, len),
+                HTTP_LINE_ENDING,
+                HTTP_LINE_ENDING
+            )
+        })
+        .collect::<Vec<_>>();
+    let tail = format!("--{}--{}", boundary, HTTP_LINE_ENDING);
+    let body_len = part_heads
+        .iter()
+        .zip(&ranges)
+        .map(|(head, range)| {
+            head.len() as u64 + (range.end() - range.start() + 1) + HTTP_LINE_ENDING.len() as u64
+        })
+        .sum::<u64>()
+        + tail.len() as u64;
+
+    let (mut writer, reader) = tokio::io::duplex(CHUNK_SIZE);
+    tokio::spawn(async move {
+        let mut file = file;
+        let result: std::io::Result<()> = async {
+            for (head, range) in part_heads.iter().zip(&ranges) {
+                writer.write_all(head.as_bytes()).await?;
+                file.seek(SeekFrom::Start(*range.start())).await?;
+                let mut part = (&mut file).take(range.end() - range.start() + 1);
+                tokio::io::copy(&mut part, &mut writer).await?;
+                writer.write_all(HTTP_LINE_ENDING.as_bytes()).await?;
+            }
+            writer.write_all(tail.as_bytes()).await?;
+            writer.shutdown().await
+        }
+        .await;
+        if let Err(e) = result {
+            error!("unable to write multipart/byteranges body. {:?}", e);
+        }
+    });
+    let multipart_type =
+        ContentType::from_static("multipart/byteranges").with_param("boundary", &boundary);
+    (Body::sized_stream(reader, body_len), multipart_type)
+}
+
+/// A boundary that won't show up in the parts, unique per response.
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default();
+    format!(
+        "{:016x}{:08x}",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn satisfiable(ranges: &[RangeInclusive<u64>]) -> ByteRanges {
+        ByteRanges::Satisfiable(ranges.to_vec())
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse("bytes=0-499", 1000), satisfiable(&[0..=499]));
+        assert_eq!(parse("bytes=500-999", 1000), satisfiable(&[500..=999]));
+        assert_eq!(parse("BYTES = 10-10", 1000), satisfiable(&[10..=10]));
+        // Past the end is clamped
+        assert_eq!(parse("bytes=900-5000", 1000), satisfiable(&[900..=999]));
+        assert_eq!(
+            parse("bytes=0-99999999999999999999999", 1000),
+            satisfiable(&[0..=999])
+        );
+    }
+
+    #[test]
+    fn parses_open_ended_and_suffix_ranges() {
+        assert_eq!(parse("bytes=990-", 1000), satisfiable(&[990..=999]));
+        assert_eq!(parse("bytes=-10", 1000), satisfiable(&[990..=999]));
+        assert_eq!(parse("bytes=-5000", 1000), satisfiable(&[0..=999]));
+    }
+
+    #[test]
+    fn sorts_and_merges_ranges() {
+        assert_eq!(
+            parse("bytes=500-599, 0-99", 1000),
+            satisfiable(&[0..=99, 500..=599])
+        );
+        // Overlapping and adjacent ones become one
+        assert_eq!(
+            parse("bytes=0-99, 50-149, 150-199, -100", 1000),
+            satisfiable(&[0..=199, 900..=999])
+        );
+        // Unsatisfiable ones among satisfiable ones are dropped
+        assert_eq!(parse("bytes=2000-, 0-0", 1000), satisfiable(&[0..=0]));
+    }
+
+    #[test]
+    fn reports_unsatisfiable_ranges() {
+        assert_eq!(parse("bytes=1000-", 1000), ByteRanges::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 1000), ByteRanges::Unsatisfiable);
+        assert_eq!(parse("bytes=0-0", 0), ByteRanges::Unsatisfiable);
+        assert_eq!(parse("bytes=-10", 0), ByteRanges::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_invalid_ranges() {
+        for range in [
+            "",
+            "bytes",
+            "bytes=",
+            "items=0-10",
+            "bytes=abc",
+            "bytes=10",
+            "bytes=-",
+            "bytes=10-5",
+            "bytes=+1-5",
+            "bytes=0-10, x",
+            "bytes=1-2-3",
+        ] {
+            assert_eq!(parse(range, 1000), ByteRanges::Ignore, "{}", range);
+        }
+    }
+
+    #[test]
+    fn ignores_too_many_ranges() {
+        let ranges = |count: u64| {
+            let specs = (0..count)
+                .map(|i| format!("{}-{}", i * 10, i * 10))
+                .collect::<Vec<_>>();
+            format!("bytes={}", specs.join(","))
+        };
+        assert!(matches!(
+            parse(&ranges(MAX_RANGES as u64), 1000),
+            ByteRanges::Satisfiable(ranges) if ranges.len() == MAX_RANGES
+        ));
+        assert_eq!(
+            parse(&ranges(MAX_RANGES as u64 + 1), 1000),
+            ByteRanges::Ignore
+        );
+    }
+
+    #[test]
+    fn formats_content_ranges() {
+        assert_eq!(content_range(&(0..=499), 1234), "bytes 0-499/1234");
+        assert_eq!(unsatisfied_range(1234), "bytes */1234");
+    }
+}
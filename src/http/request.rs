@@ -1,107 +1,407 @@
 use std::{collections::HashMap, str::FromStr};
 
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead};
 
 use crate::HTTP_LINE_ENDING;
 
-use super::{method::Method, Parse};
+use super::{
+    content_type::{self, ContentType},
+    error::ParseError,
+    header::Header,
+    header_map::HeaderMap,
+    method::Method,
+    path,
+    request_body::RequestBody,
+    status_code::StatusCode,
+    version::Version,
+    Parse,
+};
+
+/// Longest request line, line ending included.
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+/// Combined size of all header lines.
+const MAX_HEADERS_SIZE: usize = 16 * 1024;
+const MAX_HEADERS: usize = 100;
 
 #[derive(Debug)]
 pub struct Request {
     pub method: Method,
     pub path: String,
-    #[allow(dead_code)]
-    pub http_version: String,
-    pub headers: HashMap<String, String>,
+    pub query: Option<String>,
+    pub http_version: Version,
+    pub headers: HeaderMap,
     pub params: HashMap<String, String>,
-    pub body: Vec<u8>,
+    /// Representation picked from the types the route produces, see
+    /// [`HttpServerBuilder::produces`](super::server::HttpServerBuilder::produces).
+    pub response_type: Option<ContentType>,
+    pub body: RequestBody,
 }
 
 impl<R> Parse<R> for Request
 where
     R: AsyncRead + AsyncBufRead + Unpin,
 {
-    async fn parse(reader: &mut R) -> Self {
-        let start_line = StartLine::parse(reader).await;
-        let headers = Self::parse_headers(reader).await;
-        let body = Self::parse_body(reader, &headers).await;
-        Request {
+    async fn parse(reader: &mut R) -> Result<Self, ParseError> {
+        let start_line = StartLine::parse(reader).await?;
+        let headers = Self::parse_headers(reader).await?;
+        Self::check_framing(&headers, start_line.version)?;
+        let body = RequestBody::from_headers(&headers)?;
+        let (path, query) = match start_line.target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (start_line.target, None),
+        };
+        Ok(Request {
             method: start_line.method,
-            path: start_line.path,
+            path,
+            query,
             http_version: start_line.version,
-            headers: headers,
+            headers,
             params: HashMap::default(),
-            body: body,
-        }
+            response_type: None,
+            body,
+        })
     }
 }
 struct StartLine {
     method: Method,
-    path: String,
-    version: String,
+    target: String,
+    version: Version,
 }
 
 impl<R> Parse<R> for StartLine
 where
     R: AsyncRead + AsyncBufRead + Unpin,
 {
-    async fn parse(reader: &mut R) -> Self {
-        let mut line = String::new();
-        reader
-            .read_line(&mut line)
+    async fn parse(reader: &mut R) -> Result<Self, ParseError> {
+        let line = read_line_limited(reader, MAX_REQUEST_LINE)
             .await
-            .expect("unable to read status line from a request stream");
-        let line = line[..line.len() - HTTP_LINE_ENDING.len()]
-            .split(" ")
-            .collect::<Vec<&str>>();
-        let method = Method::from_str(line[0]).expect("not able to parse request method type");
-        let path = line[1].into();
-        let version = line[2].into();
-        StartLine {
+            .map_err(|e| match e {
+                ParseError::LineTooLong(_) => ParseError::RequestLineTooLong(MAX_REQUEST_LINE),
+                e => e,
+            })?;
+        // method SP request-target SP HTTP-version, with exactly one space
+        // between the parts
+        let parts = line.split(' ').collect::<Vec<&str>>();
+        let [method, target, version] = parts[..] else {
+            return Err(ParseError::InvalidRequestLine(line));
+        };
+        if target.is_empty() || !target.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(ParseError::InvalidRequestLine(line));
+        }
+        let method = Method::from_str(method)
+            .map_err(|_| ParseError::UnsupportedMethod(method.to_string()))?;
+        let version = match Version::from_str(version) {
+            Ok(version) => version,
+            Err(_) if is_http_version(version) => {
+                return Err(ParseError::UnsupportedVersion(version.to_string()))
+            }
+            Err(_) => return Err(ParseError::InvalidRequestLine(line)),
+        };
+        Ok(StartLine {
             method,
-            path,
+            target: target.to_string(),
             version,
-        }
+        })
     }
 }
 
+/// `HTTP/x.y`, whether or not we speak that version.
+fn is_http_version(version: &str) -> bool {
+    let Some(number) = version.strip_prefix("HTTP/") else {
+        return false;
+    };
+    let number = number.as_bytes();
+    number.len() == 3
+        && number[0].is_ascii_digit()
+        && number[1] == b'.'
+        && number[2].is_ascii_digit()
+}
+
 impl Request {
-    pub async fn parse_body<R>(reader: &mut R, headers: &HashMap<String, String>) -> Vec<u8>
-    where
-        R: AsyncRead + AsyncReadExt + AsyncBufRead + Unpin,
-    {
-        let length = headers
-            .get("content-length")
-            .map_or("0", |v| v.as_str())
-            .parse::<usize>()
-            .unwrap();
-        let mut body = vec![0; length];
-        reader.read_exact(&mut body).await.unwrap();
-        return body;
+    /// Media type of the body, `None` if the header is missing or invalid.
+    pub fn content_type(&self) -> Option<ContentType> {
+        self.headers
+            .get(Header::ContentType.to_str())
+            .and_then(|content_type| ContentType::from_str(content_type).ok())
+    }
+
+    /// First value of query parameter `name`, decoded. `None` when it's
+    /// missing or can't be decoded.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.as_deref()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = path::percent_decode(&key.replace('+', " ")).ok()?;
+            if key != name {
+                return None;
+            }
+            path::percent_decode(&value.replace('+', " ")).ok()
+        })
     }
 
-    pub async fn parse_headers<R>(reader: &mut R) -> HashMap<String, String>
+    /// Picks which of `available` to send back, see [`content_type::negotiate`].
+    pub fn negotiate_content_type(
+        &self,
+        available: &[ContentType],
+    ) -> Result<ContentType, StatusCode> {
+        content_type::negotiate(
+            self.headers.get_joined(Header::Accept.to_str()).as_deref(),
+            available,
+        )
+    }
+
+    /// Reads the header section. Repeated fields are kept as separate values,
+    /// except for the ones where a repetition can only be an attack.
+    pub async fn parse_headers<R>(reader: &mut R) -> Result<HeaderMap, ParseError>
     where
         R: AsyncRead + AsyncBufRead + Unpin,
     {
-        let mut headers = HashMap::<String, String>::new();
+        let mut headers = HeaderMap::new();
+        let mut size = 0;
         loop {
-            let mut cur_header = String::new();
-            if let Err(err) = reader.read_line(&mut cur_header).await {
-                println!("Error occurred while reading header: {}", err);
+            let line = read_line_limited(reader, MAX_HEADERS_SIZE - size)
+                .await
+                .map_err(|e| match e {
+                    ParseError::LineTooLong(_) => ParseError::HeadersTooLarge,
+                    e => e,
+                })?;
+            if line.is_empty() {
                 break;
             }
-            if cur_header == HTTP_LINE_ENDING || cur_header == "\n" || cur_header.is_empty() {
-                // If cur_header is empty, it means we've reached the end of headers
-                break;
+            size += line.len() + HTTP_LINE_ENDING.len();
+            if headers.len() == MAX_HEADERS {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            let (key, value) = Self::parse_header(&line)?;
+            let unique = [Header::ContentLength, Header::Host];
+            if headers.contains_key(key)
+                && unique.iter().any(|h| key.eq_ignore_ascii_case(h.to_str()))
+            {
+                return Err(ParseError::DuplicateHeader(key.to_lowercase()));
             }
-            let (key, value) = Self::parse_header(&cur_header);
-            headers.insert(key.to_lowercase(), value.to_string());
+            headers
+                .append(key, value)
+                .map_err(|_| ParseError::InvalidHeader(line.clone()))?;
         }
-        return headers;
+        Ok(headers)
     }
-    pub fn parse_header(header: &str) -> (&str, &str) {
-        let (key, value) = header.split_once(": ").unwrap();
-        (key, &value[..value.len() - HTTP_LINE_ENDING.len()])
+
+    /// Parses `field-name ":" OWS field-value OWS`.
+    pub fn parse_header(header: &str) -> Result<(&str, &str), ParseError> {
+        // obs-fold, a continuation of the previous line (RFC 9112 section 5.2)
+        if header.starts_with([' ', '\t']) {
+            return Err(ParseError::InvalidHeader(header.to_string()));
+        }
+        // Whitespace between the name and the colon (RFC 9112 section 5.1)
+        // makes the name an invalid token, `HeaderMap` rejects it.
+        let (key, value) = header
+            .split_once(':')
+            .ok_or_else(|| ParseError::InvalidHeader(header.to_string()))?;
+        Ok((key, value.trim_matches([' ', '\t'])))
+    }
+
+    /// Makes sure there's exactly one way to tell where the body ends
+    /// (RFC 9112 section 6.3). Anything else could be interpreted differently
+    /// by a proxy in front of us.
+    fn check_framing(headers: &HeaderMap, version: Version) -> Result<(), ParseError> {
+        let transfer_encoding = headers.get(Header::TransferEncoding.to_str());
+        if transfer_encoding.is_some() && headers.contains_key(Header::ContentLength.to_str()) {
+            return Err(ParseError::ConflictingFraming);
+        }
+        if transfer_encoding.is_some() && version == Version::Http10 {
+            return Err(ParseError::ConflictingFraming);
+        }
+        if version == Version::Http11 && !headers.contains_key(Header::Host.to_str()) {
+            return Err(ParseError::MissingHost);
+        }
+        Ok(())
+    }
+}
+
+/// Reads a single line of at most `limit` bytes (line ending included) and
+/// returns it without the line ending.
+pub(super) async fn read_line_limited<R>(reader: &mut R, limit: usize) -> Result<String, ParseError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = vec![];
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let (consumed, done) = match buf.iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), false),
+        };
+        if line.len() + consumed > limit {
+            return Err(ParseError::LineTooLong(limit));
+        }
+        line.extend_from_slice(&buf[..consumed]);
+        reader.consume(consumed);
+        if done {
+            break;
+        }
+    }
+    // Only CRLF ends a line, a bare LF or CR is a common way to make two
+    // parsers disagree on where a line ends.
+    if !line.ends_with(HTTP_LINE_ENDING.as_bytes()) || line[..line.len() - 2].contains(&b'\r') {
+        return Err(ParseError::InvalidLineEnding);
+    }
+    line.truncate(line.len() - HTTP_LINE_ENDING.len());
+    String::from_utf8(line)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    async fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::parse(&mut raw.as_bytes()).await
+    }
+
+    async fn status_of(raw: &str) -> StatusCode {
+        parse(raw)
+            .await
+            .expect_err("request should be rejected")
+            .status_code()
+    }
+
+    #[tokio::test]
+    async fn parses_a_valid_request() {
+        let request =
+            parse("POST /files/a?x=1 HTTP/1.1\r\nHost: h\r\nContent-Length: 3\r\n\r\nabc")
+                .await
+                .expect("request should parse");
+        assert!(matches!(request.method, Method::Post));
+        assert_eq!(request.path, "/files/a");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+        assert_eq!(request.body.len(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn rejects_duplicate_content_length() {
+        let raw = "POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 3\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(status_of(raw).await, StatusCode::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn rejects_content_length_with_transfer_encoding() {
+        let raw =
+            "POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(status_of(raw).await, StatusCode::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn rejects_whitespace_before_colon() {
+        let raw = "POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding : chunked\r\n\r\n";
+        assert_eq!(status_of(raw).await, StatusCode::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn rejects_obs_fold() {
+        let raw = "GET / HTTP/1.1\r\nHost: h\r\nX-Long: a\r\n b\r\n\r\n";
+        assert_eq!(status_of(raw).await, StatusCode::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn rejects_bare_line_endings() {
+        for raw in [
+            "GET / HTTP/1.1\nHost: h\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: h\n\r\n",
+            "GET / HTTP/1.1\r\nHost: h\rX: y\r\n\r\n",
+        ] {
+            assert_eq!(status_of(raw).await, StatusCode::BadRequest, "{:?}", raw);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_codings_split_across_transfer_encoding_lines() {
+        let raw = "POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(status_of(raw).await, StatusCode::NotImplemented);
+    }
+
+    #[tokio::test]
+    async fn rejects_chunked_that_isnt_the_final_coding() {
+        let raw = "POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked, gzip\r\n\r\n";
+        assert_eq!(status_of(raw).await, StatusCode::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn rejects_transfer_encoding_in_http_1_0() {
+        let raw = "POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(status_of(raw).await, StatusCode::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn rejects_signed_content_length() {
+        let raw = "POST / HTTP/1.1\r\nHost: h\r\nContent-Length: +3\r\n\r\n";
+        assert_eq!(status_of(raw).await, StatusCode::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_host() {
+        assert_eq!(
+            status_of("GET / HTTP/1.1\r\n\r\n").await,
+            StatusCode::BadRequest
+        );
+        assert!(parse("GET / HTTP/1.0\r\n\r\n").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn limits_request_line_length() {
+        let line = |len: usize| {
+            let prefix = "GET /";
+            let suffix = " HTTP/1.1\r\n";
+            format!(
+                "{}{}{}Host: h\r\n\r\n",
+                prefix,
+                "a".repeat(len - prefix.len() - suffix.len()),
+                suffix
+            )
+        };
+        assert!(parse(&line(MAX_REQUEST_LINE)).await.is_ok());
+        assert_eq!(
+            status_of(&line(MAX_REQUEST_LINE + 1)).await,
+            StatusCode::UriTooLong
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_header_section_size() {
+        // `Host: h` plus one header filling the rest, CRLFs included
+        let headers = |len: usize| {
+            let host = "Host: h\r\n";
+            let name = "X-Fill: ";
+            format!(
+                "GET / HTTP/1.1\r\n{}{}{}\r\n\r\n",
+                host,
+                name,
+                "a".repeat(len - host.len() - name.len() - 2)
+            )
+        };
+        // The blank line ending the section counts as well
+        assert!(parse(&headers(MAX_HEADERS_SIZE - 2)).await.is_ok());
+        assert_eq!(
+            status_of(&headers(MAX_HEADERS_SIZE - 1)).await,
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_header_count() {
+        let headers = |count: usize| {
+            let fields = (1..count)
+                .map(|i| format!("X-{}: a\r\n", i))
+                .collect::<String>();
+            format!("GET / HTTP/1.1\r\nHost: h\r\n{}\r\n", fields)
+        };
+        assert!(parse(&headers(MAX_HEADERS)).await.is_ok());
+        assert_eq!(
+            status_of(&headers(MAX_HEADERS + 1)).await,
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
     }
 }
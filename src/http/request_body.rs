@@ -0,0 +1,517 @@
+use std::{str::FromStr, sync::Arc};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use super::{
+    encoding::{Decoder, DecompressionOptions, Encoding, RATIO_GRACE},
+    error::ParseError,
+    header::Header,
+    header_map::HeaderMap,
+    request::{read_line_limited, Request},
+    status_code::StatusCode,
+};
+use crate::HTTP_LINE_ENDING;
+
+/// Longest chunk-size line, including chunk extensions.
+const MAX_CHUNK_LINE: usize = 4 * 1024;
+/// Combined size of all trailer lines after a chunked body.
+const MAX_TRAILERS_SIZE: usize = 16 * 1024;
+/// Compressed bytes fed to a decoder at once. The size limits are checked
+/// after every step, and each step's output is capped to what is left of
+/// them, see [`Decoder::write`].
+const DECODE_STEP: usize = 512;
+
+pub type BodyReader = Box<dyn AsyncBufRead + Send + Sync + Unpin>;
+/// The response side of the connection, shared with the server so the body
+/// can send `100 Continue` on its own.
+pub type ContinueWriter = Arc<Mutex<dyn AsyncWrite + Send + Unpin>>;
+
+/// How the end of the request body is determined.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Framing {
+    Length(u64),
+    Chunked,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    /// Bytes left of a `content-length` body or of the current chunk.
+    Remaining(u64),
+    /// The current chunk is done, its CRLF and the next size line follow.
+    ChunkEnd,
+    /// The first chunk-size line hasn't been read yet.
+    ChunkStart,
+    Done,
+}
+
+/// The request body, read lazily from the connection as the handler asks
+/// for it. Nothing is read if the handler doesn't touch the body.
+pub struct RequestBody {
+    reader: Option<BodyReader>,
+    framing: Framing,
+    state: State,
+    limit: u64,
+    read: u64,
+    trailers: HeaderMap,
+    /// Set while the client waits for `100 Continue` before sending the body.
+    expect_continue: Option<ContinueWriter>,
+    /// Undoes the body's `content-encoding`, see [`RequestBody::decode`].
+    decoder: Option<(Encoding, Decoder)>,
+    decompression: DecompressionOptions,
+    decoded: u64,
+}
+
+impl std::fmt::Debug for RequestBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestBody")
+            .field("framing", &self.framing)
+            .field("state", &self.state)
+            .field("read", &self.read)
+            .finish()
+    }
+}
+
+impl RequestBody {
+    /// Works out the framing from `transfer-encoding`/`content-length`. The
+    /// body can't be read until the server attaches the connection to it.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, ParseError> {
+        let framing = if let Some(transfer_encoding) =
+            headers.get_joined(Header::TransferEncoding.to_str())
+        {
+            let codings = transfer_encoding
+                .split(',')
+                .map(|coding| coding.trim().to_lowercase())
+                .collect::<Vec<_>>();
+            // A request body whose length can't be determined is an error
+            // (RFC 9112 section 6.3), so chunked has to be the final coding.
+            if codings.last().map(String::as_str) != Some("chunked") {
+                return Err(ParseError::InvalidTransferEncoding(
+                    transfer_encoding.clone(),
+                ));
+            }
+            if codings.len() > 1 {
+                return Err(ParseError::UnsupportedTransferEncoding(
+                    transfer_encoding.clone(),
+                ));
+            }
+            Framing::Chunked
+        } else {
+            let length = match headers.get(Header::ContentLength.to_str()) {
+                None => 0,
+                // 1*DIGIT, `parse` alone would also accept a leading `+`
+                Some(length) if length.bytes().all(|b| b.is_ascii_digit()) => length
+                    .parse::<u64>()
+                    .map_err(|_| ParseError::InvalidContentLength(length.to_string()))?,
+                Some(length) => return Err(ParseError::InvalidContentLength(length.to_string())),
+            };
+            Framing::Length(length)
+        };
+        let state = match framing {
+            Framing::Length(length) => State::Remaining(length),
+            Framing::Chunked => State::ChunkStart,
+        };
+        Ok(RequestBody {
+            reader: None,
+            framing,
+            state,
+            limit: u64::MAX,
+            read: 0,
+            trailers: HeaderMap::new(),
+            expect_continue: None,
+            decoder: None,
+            decompression: DecompressionOptions::default(),
+            decoded: 0,
+        })
+    }
+
+    pub(super) fn attach(&mut self, reader: BodyReader, limit: u64) {
+        self.reader = Some(reader);
+        self.limit = limit;
+    }
+
+    /// The client sent `Expect: 100-continue`. The interim response is only
+    /// sent once the body is actually read, so a handler or middleware can
+    /// still refuse the request before the client transfers anything.
+    pub(super) fn expect_continue(&mut self, writer: ContinueWriter) {
+        if self.state != State::Remaining(0) {
+            self.expect_continue = Some(writer);
+        }
+    }
+
+    /// Decompress the body according to its `content-encoding` while it's
+    /// read. Fails for codings we can't undo, or when more than one was
+    /// applied.
+    pub(super) fn decode(
+        &mut self,
+        content_encoding: &str,
+        options: &DecompressionOptions,
+    ) -> Result<(), ParseError> {
+        let codings = content_encoding
+            .split(',')
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+            .collect::<Vec<_>>();
+        let encoding = match codings[..] {
+            [] => return Ok(()),
+            [coding] => Encoding::from_str(coding).map_err(|_| {
+                ParseError::UnsupportedContentEncoding(content_encoding.to_string())
+            })?,
+            _ => {
+                return Err(ParseError::UnsupportedContentEncoding(
+                    content_encoding.to_string(),
+                ))
+            }
+        };
+        self.decoder = Some((encoding, Decoder::new(encoding)?));
+        self.decompression = options.clone();
+        Ok(())
+    }
+
+    /// Size announced by `content-length`. `None` for chunked bodies, and
+    /// for compressed ones since the decompressed size isn't known.
+    pub fn len(&self) -> Option<u64> {
+        if self.decoder.is_some() {
+            return None;
+        }
+        match self.framing {
+            Framing::Length(length) => Some(length),
+            Framing::Chunked => None,
+        }
+    }
+
+    /// Trailer fields of a chunked body, available once it was read to the
+    /// end.
+    #[allow(dead_code)]
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+
+    /// Next piece of the body as it arrives, `None` once it's complete.
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        if self.decoder.is_none() {
+            return self.raw_chunk().await;
+        }
+        loop {
+            let Some(raw) = self.raw_chunk().await? else {
+                let Some((encoding, decoder)) = self.decoder.take() else {
+                    return Ok(None);
+                };
+                let rest = decoder
+                    .finish()
+                    .map_err(|_| ParseError::InvalidEncodedBody(encoding.to_str()))?;
+                self.count_decoded(rest.len())?;
+                return Ok(Some(rest).filter(|rest| !rest.is_empty()));
+            };
+            let mut decoded = vec![];
+            for step in raw.chunks(DECODE_STEP) {
+                let (encoding, decoder) = self.decoder.as_mut().expect("decoder was checked");
+                // One byte over is enough to tell the limit was crossed
+                let budget = self
+                    .decompression
+                    .max_size
+                    .saturating_sub(self.decoded)
+                    .saturating_add(1);
+                let output = decoder
+                    .write(step, usize::try_from(budget).unwrap_or(usize::MAX))
+                    .map_err(|_| ParseError::InvalidEncodedBody(encoding.to_str()))?;
+                self.count_decoded(output.len())?;
+                decoded.extend(output);
+            }
+            if !decoded.is_empty() {
+                return Ok(Some(decoded));
+            }
+        }
+    }
+
+    /// Enforces the decompression limits on `n` more decompressed bytes.
+    fn count_decoded(&mut self, n: usize) -> Result<(), ParseError> {
+        self.decoded += n as u64;
+        if self.decoded > self.decompression.max_size {
+            return Err(ParseError::BodyTooLarge(self.decompression.max_size));
+        }
+        if self.decoded > RATIO_GRACE
+            && self.decoded > self.read.saturating_mul(self.decompression.max_ratio)
+        {
+            return Err(ParseError::CompressionRatioTooHigh(
+                self.decompression.max_ratio,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Next piece of the body as sent, before any decompression.
+    async fn raw_chunk(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        if let Some(writer) = self.expect_continue.take() {
+            let mut writer = writer.lock().await;
+            let status_line = format!(
+                "HTTP/1.1 {}{}{}",
+                StatusCode::Continue.status_line(),
+                HTTP_LINE_ENDING,
+                HTTP_LINE_ENDING
+            );
+            writer.write_all(status_line.as_bytes()).await?;
+            writer.flush().await?;
+        }
+        loop {
+            match self.state {
+                State::Done => return Ok(None),
+                State::Remaining(0) => match self.framing {
+                    Framing::Length(_) => self.state = State::Done,
+                    Framing::Chunked => self.state = State::ChunkEnd,
+                },
+                State::Remaining(remaining) => {
+                    let reader = self.reader()?;
+                    let buf = reader.fill_buf().await?;
+                    if buf.is_empty() {
+                        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                    }
+                    let n = buf.len().min(remaining as usize);
+                    let chunk = buf[..n].to_vec();
+                    reader.consume(n);
+                    self.state = State::Remaining(remaining - n as u64);
+                    self.read += n as u64;
+                    return Ok(Some(chunk));
+                }
+                State::ChunkEnd => {
+                    let missing_crlf =
+                        || ParseError::MalformedChunk("chunk data not followed by CRLF");
+                    let line = read_line_limited(self.reader()?, HTTP_LINE_ENDING.len())
+                        .await
+                        .map_err(|e| match e {
+                            ParseError::LineTooLong(_) | ParseError::InvalidLineEnding => {
+                                missing_crlf()
+                            }
+                            e => e,
+                        })?;
+                    if !line.is_empty() {
+                        return Err(missing_crlf());
+                    }
+                    self.state = State::ChunkStart;
+                }
+                State::ChunkStart => {
+                    let size = self.read_chunk_size().await?;
+                    if size == 0 {
+                        self.read_trailers().await?;
+                        self.state = State::Done;
+                    } else if self
+                        .read
+                        .checked_add(size)
+                        .map_or(true, |total| total > self.limit)
+                    {
+                        return Err(ParseError::BodyTooLarge(self.limit));
+                    } else {
+                        self.state = State::Remaining(size);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the whole body into memory.
+    #[allow(dead_code)]
+    pub async fn bytes(&mut self) -> Result<Vec<u8>, ParseError> {
+        let mut body = vec![];
+        while let Some(chunk) = self.chunk().await? {
+            body.extend(chunk);
+        }
+        Ok(body)
+    }
+
+    /// Pipes the body into `writer` without holding more than one piece of
+    /// it in memory. Returns the number of bytes written.
+    #[allow(dead_code)]
+    pub async fn copy_to<W>(&mut self, writer: &mut W) -> Result<u64, ParseError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut written = 0;
+        while let Some(chunk) = self.chunk().await? {
+            writer.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+
+    fn reader(&mut self) -> Result<&mut BodyReader, ParseError> {
+        self.reader
+            .as_mut()
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotConnected).into())
+    }
+
+    /// chunk-size [ chunk-ext ] CRLF. Chunk extensions are ignored.
+    async fn read_chunk_size(&mut self) -> Result<u64, ParseError> {
+        let line = read_line_limited(self.reader()?, MAX_CHUNK_LINE).await?;
+        let size = line.split(';').next().unwrap_or_default().trim_end();
+        if size.is_empty() || size.len() > 16 || !size.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseError::MalformedChunk("invalid chunk size"));
+        }
+        u64::from_str_radix(size, 16).map_err(|_| ParseError::MalformedChunk("invalid chunk size"))
+    }
+
+    async fn read_trailers(&mut self) -> Result<(), ParseError> {
+        let mut trailers_size = 0;
+        loop {
+            let line = read_line_limited(self.reader()?, MAX_TRAILERS_SIZE - trailers_size).await?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            trailers_size += line.len() + HTTP_LINE_ENDING.len();
+            let (key, value) = Request::parse_header(&line)?;
+            self.trailers
+                .append(key, value)
+                .map_err(|_| ParseError::InvalidHeader(line.clone()))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const LIMIT: u64 = 64 * 1024 * 1024;
+
+    fn body(headers: &[(&str, &str)], data: &'static [u8], limit: u64) -> RequestBody {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(name, value).unwrap();
+        }
+        let mut body = RequestBody::from_headers(&map).unwrap();
+        body.attach(Box::new(data), limit);
+        body
+    }
+
+    fn chunked(data: &'static [u8]) -> RequestBody {
+        body(&[("Transfer-Encoding", "chunked")], data, LIMIT)
+    }
+
+    #[tokio::test]
+    async fn reads_content_length_bodies() {
+        let mut request = body(&[("Content-Length", "5")], b"hello world", LIMIT);
+        assert_eq!(request.len(), Some(5));
+        assert_eq!(request.bytes().await.unwrap(), b"hello");
+        assert_eq!(request.chunk().await.unwrap(), None);
+
+        let mut request = body(&[], b"ignored", LIMIT);
+        assert_eq!(request.bytes().await.unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn fails_on_truncated_bodies() {
+        let mut request = body(&[("Content-Length", "10")], b"hello", LIMIT);
+        assert!(matches!(
+            request.bytes().await,
+            Err(ParseError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+        let mut request = chunked(b"5\r\nhel");
+        assert!(matches!(request.bytes().await, Err(ParseError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn reads_chunks_ignoring_extensions() {
+        let mut request =
+            chunked(b"5;name=value\r\nhello\r\n6 ; x\r\n world\r\nA\r\n0123456789\r\n0\r\n\r\n");
+        assert_eq!(request.len(), None);
+        assert_eq!(request.chunk().await.unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(request.chunk().await.unwrap(), Some(b" world".to_vec()));
+        assert_eq!(request.chunk().await.unwrap(), Some(b"0123456789".to_vec()));
+        assert_eq!(request.chunk().await.unwrap(), None);
+        assert!(request.trailers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn collects_trailers() {
+        let mut request = chunked(b"3\r\nabc\r\n0\r\nX-Checksum: 123\r\nX-Other: a\r\n\r\n");
+        assert_eq!(request.bytes().await.unwrap(), b"abc");
+        assert_eq!(request.trailers().get("x-checksum"), Some("123"));
+        assert_eq!(request.trailers().get("X-OTHER"), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn rejects_chunk_data_without_crlf() {
+        let mut request = chunked(b"5\r\nhelloX\r\n0\r\n\r\n");
+        assert!(matches!(
+            request.bytes().await,
+            Err(ParseError::MalformedChunk(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_size_lines() {
+        for data in [
+            &b"\r\nhello\r\n0\r\n\r\n"[..],
+            b"g\r\n",
+            b" 5\r\nhello\r\n0\r\n\r\n",
+            b"-5\r\n",
+            b"0x5\r\n",
+            b"10000000000000000\r\n",
+        ] {
+            let mut request = chunked(data);
+            assert!(
+                matches!(request.bytes().await, Err(ParseError::MalformedChunk(_))),
+                "{:?}",
+                String::from_utf8_lossy(data)
+            );
+        }
+        let mut request = chunked(b"5\nhello\r\n0\r\n\r\n");
+        assert!(matches!(
+            request.bytes().await,
+            Err(ParseError::InvalidLineEnding)
+        ));
+    }
+
+    #[tokio::test]
+    async fn limits_the_size_line() {
+        let line = format!("1;{}\r\na\r\n0\r\n\r\n", "x".repeat(MAX_CHUNK_LINE));
+        let mut request = chunked(line.leak().as_bytes());
+        assert!(matches!(
+            request.bytes().await,
+            Err(ParseError::LineTooLong(MAX_CHUNK_LINE))
+        ));
+    }
+
+    #[tokio::test]
+    async fn limits_the_trailers() {
+        let trailer = format!("X-Padding: {}\r\n", "x".repeat(1000));
+        let data = format!("0\r\n{}\r\n", trailer.repeat(MAX_TRAILERS_SIZE / 1000 + 1));
+        let mut request = chunked(data.leak().as_bytes());
+        assert!(matches!(
+            request.bytes().await,
+            Err(ParseError::LineTooLong(_))
+        ));
+        // Just under the limit is fine
+        let data = format!(
+            "0\r\n{}\r\n",
+            trailer.repeat(MAX_TRAILERS_SIZE / trailer.len())
+        );
+        let mut request = chunked(data.leak().as_bytes());
+        assert!(request.bytes().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn limits_the_body() {
+        let data = b"4\r\nabcd\r\n4\r\nefgh\r\n0\r\n\r\n";
+        let mut request = body(&[("Transfer-Encoding", "chunked")], data, 8);
+        assert_eq!(request.bytes().await.unwrap(), b"abcdefgh");
+        let mut request = body(&[("Transfer-Encoding", "chunked")], data, 7);
+        assert!(matches!(
+            request.bytes().await,
+            Err(ParseError::BodyTooLarge(7))
+        ));
+    }
+
+    #[tokio::test]
+    async fn does_not_overflow_on_huge_chunk_sizes() {
+        let mut request = chunked(b"1\r\na\r\nffffffffffffffff\r\n");
+        assert_eq!(request.chunk().await.unwrap(), Some(b"a".to_vec()));
+        assert!(matches!(
+            request.chunk().await,
+            Err(ParseError::BodyTooLarge(LIMIT))
+        ));
+    }
+}
@@ -1,116 +1,623 @@
-use std::io::Write;
+use std::{str::FromStr, time::SystemTime};
 
-use flate2::write::GzEncoder;
+use itertools::Itertools;
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::tcp::OwnedWriteHalf,
+    sync::oneshot,
+};
+use tracing::{debug, error};
 
 use crate::HTTP_LINE_ENDING;
 
 use super::{
-    content_type::ContentType, encoding::Encoding, header::Header, status_code::StatusCode,
+    body::{Body, CHUNK_SIZE},
+    conditional::{Conditions, Validators},
+    content_type::ContentType,
+    date,
+    encoding::{self, CompressionLevel, CompressionOptions, Encoder, Encoding},
+    etag::ETag,
+    header::Header,
+    header_map::HeaderMap,
+    status_code::StatusCode,
+    version::Version,
 };
 
+#[cfg(target_os = "linux")]
+use super::sendfile;
+
+/// Trailer fields sent after a chunked body, once the handler knows them.
+pub type Trailers = oneshot::Receiver<HeaderMap>;
+
 pub struct Response {
-    body: Option<Vec<u8>>,
+    body: Body,
     status_code: StatusCode,
-    content_type: ContentType,
-    accept_encoding: Option<Encoding>,
-    content_length: Option<usize>,
+    headers: HeaderMap,
+    trailer_names: Vec<String>,
+    trailers: Option<Trailers>,
+    /// Answer to `HEAD`: the head says what `GET` would send, nothing follows.
+    head_only: bool,
+}
+
+/// How the end of the body is communicated to the client.
+enum Framing {
+    ContentLength(u64),
+    Chunked,
+    /// HTTP/1.0 has no chunked coding, the body ends when the connection does.
+    Close,
+    /// 1xx, 204 and 304 responses end with the header section.
+    NoBody,
 }
 
 impl Response {
     pub fn builder() -> ResponseBuilder {
         ResponseBuilder::new()
     }
-    pub fn as_byte(&self) -> Vec<u8> {
+    /// Picks the content coding from `accept_encoding`, the request's
+    /// `Accept-Encoding`, and sets the headers that depend on it:
+    /// `Content-Encoding`, `Vary` and the `ETag` of the encoded
+    /// representation. Runs before [`Response::revalidate`] so a `304 Not
+    /// Modified` carries what the `200` would. The body itself is only
+    /// encoded by [`Response::encode`].
+    ///
+    /// Responses that already have a `Content-Encoding` are left alone.
+    /// Already-compressed media and bodies smaller than
+    /// [`CompressionOptions::min_size`] are only encoded when the client
+    /// refuses `identity`.
+    pub(super) fn negotiate_encoding(
+        &mut self,
+        accept_encoding: Option<&str>,
+        options: &CompressionOptions,
+    ) -> Option<Encoding> {
+        if !options.enabled
+            || !self.status_code.allows_body()
+            || self.status_code == StatusCode::PartialContent
+            || matches!(self.body, Body::Empty)
+            || self.headers.contains_key(Header::ContentEncoding.to_str())
+        {
+            return None;
+        }
+        let compressible = self
+            .headers
+            .get(Header::ContentType.to_str())
+            .and_then(|content_type| ContentType::from_str(content_type).ok())
+            .map_or(true, |content_type| content_type.is_compressible());
+        let worth_it = compressible
+            && self
+                .body
+                .len()
+                .map_or(true, |length| length >= options.min_size);
+
+        // From here on the representation depends on `Accept-Encoding`, even
+        // for small bodies: a client refusing `identity` gets them encoded or
+        // `406 Not Acceptable`
+        self.vary(Header::AcceptEncoding.to_str());
+        let encoding = match encoding::negotiate(accept_encoding, !worth_it) {
+            Ok(encoding) => encoding?,
+            Err(status_code) => {
+                self.status_code = status_code;
+                self.body = Body::Empty;
+                self.headers.remove(Header::ContentType.to_str());
+                return None;
+            }
+        };
+        self.set_header(Header::ContentEncoding.to_str(), encoding.to_str());
+        // Each coding is a representation of its own, with its own tag
+        if let Some(etag) = self
+            .headers
+            .get(Header::ETag.to_str())
+            .and_then(|etag| ETag::from_str(etag).ok())
+        {
+            self.set_header(Header::ETag.to_str(), &etag.encoded(encoding).to_string());
+        }
+        Some(encoding)
+    }
+    /// Encodes the body with the coding [`Response::negotiate_encoding`]
+    /// picked, unless the response has no body by now, e.g. `304 Not
+    /// Modified`. Streamed and file bodies are compressed as they are sent,
+    /// call [`Response::omit_body`] first for `HEAD` so they aren't read.
+    pub(super) fn encode(
+        mut self,
+        encoding: Option<Encoding>,
+        level: CompressionLevel,
+    ) -> Response {
+        let Some(encoding) = encoding else {
+            return self;
+        };
+        if !self.status_code.allows_body() || matches!(self.body, Body::Empty) {
+            return self;
+        }
+        self.body = match self.body {
+            Body::Full(bytes) => match encoding.encode(&bytes, level) {
+                Ok(encoded) => Body::Full(encoded),
+                Err(e) => {
+                    // Sent as is, without the validator of the encoded form
+                    error!("unable to compress response body. {:?}", e);
+                    self.body = Body::Full(bytes);
+                    for header in [Header::ContentEncoding, Header::ETag] {
+                        self.headers.remove(header.to_str());
+                    }
+                    return self;
+                }
+            },
+            // `HEAD` only needs the framing `GET` would get, unknown length
+            _ if self.head_only => Body::stream(tokio::io::empty()),
+            body => {
+                let (mut writer, encoded) = Body::channel(CHUNK_SIZE);
+                tokio::spawn(async move {
+                    match compress_stream(body, encoding, level, &mut writer).await {
+                        Ok(()) => {}
+                        // The client went away mid-body
+                        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                            debug!("compressed body dropped before the end")
+                        }
+                        Err(e) => error!("unable to compress response body. {:?}", e),
+                    }
+                });
+                encoded
+            }
+        };
+        self
+    }
+    /// Checks the preconditions of a `GET` against the response's `ETag` and
+    /// `Last-Modified`: answers `304 Not Modified` when the client's cached
+    /// copy is still current, `412 Precondition Failed` when `If-Match` or
+    /// `If-Unmodified-Since` don't hold. Handlers of other methods check
+    /// [`Conditions::precondition_failed`] themselves, before making changes.
+    pub(super) fn revalidate(mut self, conditions: &Conditions) -> Response {
+        if !conditions.is_cacheable()
+            || !matches!(
+                self.status_code,
+                StatusCode::Ok | StatusCode::PartialContent
+            )
+        {
+            return self;
+        }
+        let current = Validators {
+            etag: self
+                .headers
+                .get(Header::ETag.to_str())
+                .and_then(|etag| ETag::from_str(etag).ok()),
+            last_modified: self
+                .headers
+                .get(Header::LastModified.to_str())
+                .and_then(date::parse),
+        };
+        self.status_code = if conditions.precondition_failed(Some(&current)) {
+            StatusCode::PreconditionFailed
+        } else if conditions.not_modified(&current) {
+            StatusCode::NotModified
+        } else {
+            return self;
+        };
+        self.body = Body::Empty;
+        // Validators, `Vary` and caching headers stay (RFC 9110 section 15.4.5)
+        for header in [
+            Header::ContentType,
+            Header::ContentRange,
+            Header::ContentEncoding,
+        ] {
+            self.headers.remove(header.to_str());
+        }
+        self
+    }
+    /// Sends only the head, for `HEAD` requests. The framing headers are still
+    /// those of the body.
+    pub(super) fn omit_body(&mut self) {
+        self.head_only = true;
+    }
+    /// Sets `name` unless the handler already did.
+    pub(super) fn default_header(&mut self, name: &str, value: &str) {
+        if self.headers.contains_key(name) {
+            return;
+        }
+        self.set_header(name, value);
+    }
+    fn set_header(&mut self, name: &str, value: &str) {
+        if let Err(e) = self.headers.insert(name, value) {
+            error!("Ignoring default header: {}", e);
+        }
+    }
+    /// Adds request header `name` to `Vary`, unless it's already listed.
+    pub(super) fn vary(&mut self, name: &str) {
+        let listed = self
+            .headers
+            .get_joined(Header::Vary.to_str())
+            .is_some_and(|vary| {
+                vary.split(',')
+                    .map(str::trim)
+                    .any(|listed| listed == "*" || listed.eq_ignore_ascii_case(name))
+            });
+        if listed {
+            return;
+        }
+        if let Err(e) = self.headers.append(Header::Vary.to_str(), name) {
+            error!("Ignoring default header: {}", e);
+        }
+    }
+    /// Status line and headers, including the blank line that ends them.
+    fn head(&self, framing: &Framing) -> Vec<u8> {
         let mut msg_lines = vec![];
         // Start Line
         msg_lines.push(format!("HTTP/1.1 {}", self.status_code.status_line()));
         // Headers
-        msg_lines.push(format!(
-            "{}: {}",
-            Header::ContentType.to_str(),
-            self.content_type.to_str()
-        ));
-        if let Some(accept_encoding) = &self.accept_encoding {
-            msg_lines.push(format!(
-                "{}: {}",
-                Header::ContentEncoding.to_str(),
-                accept_encoding.to_str()
-            ));
+        for (name, value) in self.headers.iter() {
+            msg_lines.push(format!("{}: {}", name, value));
         }
-        if let Some(content_length) = &self.content_length {
-            msg_lines.push(format!(
+        match framing {
+            Framing::ContentLength(content_length) => msg_lines.push(format!(
                 "{}: {}",
                 Header::ContentLength.to_str(),
                 content_length
-            ));
+            )),
+            Framing::Chunked => {
+                msg_lines.push(format!("{}: chunked", Header::TransferEncoding.to_str()));
+                if !self.trailer_names.is_empty() {
+                    msg_lines.push(format!(
+                        "{}: {}",
+                        Header::Trailer.to_str(),
+                        self.trailer_names.join(", ")
+                    ));
+                }
+            }
+            Framing::Close => {
+                msg_lines.push(format!("{}: close", Header::Connection.to_str()));
+            }
+            Framing::NoBody => {}
         }
         msg_lines.push(HTTP_LINE_ENDING.to_string());
-        // Body
-        let mut response_bytes = msg_lines.join(HTTP_LINE_ENDING).as_bytes().to_vec();
-        if let Some(body) = &self.body {
-            response_bytes.extend(body);
+        msg_lines.join(HTTP_LINE_ENDING).into_bytes()
+    }
+    /// Writes the response to `writer`, framed for a client speaking
+    /// `version`. Bodies of unknown length are sent chunked to HTTP/1.1
+    /// clients and delimited by closing the connection for HTTP/1.0 ones.
+    ///
+    /// Only in-memory bodies are sent together with the head, streamed and
+    /// file bodies follow it as they are read.
+    pub async fn write_to<W>(self, writer: &mut W, version: Version) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let framing = match (self.body.len(), version) {
+            _ if !self.status_code.allows_body() => Framing::NoBody,
+            (Some(length), _) => Framing::ContentLength(length),
+            (None, Version::Http11) => Framing::Chunked,
+            (None, Version::Http10) => Framing::Close,
+        };
+        let mut head = self.head(&framing);
+        match (framing, self.body) {
+            (Framing::NoBody, _) => writer.write_all(&head).await?,
+            _ if self.head_only => writer.write_all(&head).await?,
+            (Framing::Chunked, body) => {
+                writer.write_all(&head).await?;
+                body.write_chunks_to(writer).await?;
+                let trailers = match self.trailers {
+                    Some(trailers) => trailers.await.unwrap_or_default(),
+                    None => HeaderMap::new(),
+                };
+                let mut last_chunk = format!("0{}", HTTP_LINE_ENDING);
+                trailers
+                    .iter()
+                    // Only send what was announced in the `Trailer` header
+                    .filter(|(name, _)| {
+                        self.trailer_names
+                            .iter()
+                            .any(|declared| declared.eq_ignore_ascii_case(name))
+                    })
+                    .for_each(|(name, value)| {
+                        last_chunk.push_str(&format!("{}: {}{}", name, value, HTTP_LINE_ENDING))
+                    });
+                last_chunk.push_str(HTTP_LINE_ENDING);
+                writer.write_all(last_chunk.as_bytes()).await?;
+            }
+            (_, Body::Full(bytes)) => {
+                head.extend(bytes);
+                writer.write_all(&head).await?;
+            }
+            (_, body) => {
+                writer.write_all(&head).await?;
+                body.write_to(writer).await?;
+            }
+        }
+        writer.flush().await
+    }
+    /// [`Response::write_to`] for a client connection. With `sendfile` on
+    /// Linux, file bodies are handed to the kernel instead of being copied
+    /// through userspace. Compressed files are streams by now and aren't
+    /// concerned.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    pub async fn send(
+        self,
+        writer: &mut OwnedWriteHalf,
+        version: Version,
+        sendfile: bool,
+    ) -> std::io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if sendfile && !self.head_only && self.status_code.allows_body() {
+            if let Body::File { len, .. } = self.body {
+                let head = self.head(&Framing::ContentLength(len));
+                let Body::File { file, offset, len } = self.body else {
+                    unreachable!("body was just matched as a file");
+                };
+                writer.write_all(&head).await?;
+                return sendfile::send_file(writer, file, offset, len).await;
+            }
         }
-        response_bytes
+        self.write_to(writer, version).await
     }
 }
 
+/// Reads `body` through an [`Encoder`] into `writer`. Dropping `writer`
+/// afterwards ends the compressed body.
+async fn compress_stream<W>(
+    body: Body,
+    encoding: Encoding,
+    level: CompressionLevel,
+    writer: &mut W,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = body.into_reader().await?;
+    let mut encoder = Encoder::new(encoding, level)?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let encoded = encoder.write(&buf[..n])?;
+        if !encoded.is_empty() {
+            writer.write_all(&encoded).await?;
+        }
+    }
+    writer.write_all(&encoder.finish()?).await?;
+    writer.shutdown().await
+}
+
 pub struct ResponseBuilder {
     status_code: StatusCode,
-    body: Option<Vec<u8>>,
-    content_type: ContentType,
-    accept_encoding: Option<Encoding>,
-    content_length: Option<usize>,
+    body: Body,
+    headers: HeaderMap,
+    trailer_names: Vec<String>,
+    trailers: Option<Trailers>,
 }
 
 impl ResponseBuilder {
     fn new() -> Self {
         ResponseBuilder {
             status_code: StatusCode::Ok,
-            body: None,
-            content_type: ContentType::Plain,
-            accept_encoding: None,
-            content_length: None,
+            body: Body::Empty,
+            headers: HeaderMap::new(),
+            trailer_names: vec![],
+            trailers: None,
         }
     }
-    pub fn accept_encoding(mut self, accept_encoding: Option<Encoding>) -> Self {
-        self.accept_encoding = accept_encoding;
-        self
-    }
     pub fn status_code(mut self, status_code: StatusCode) -> Self {
         self.status_code = status_code;
         self
     }
-    pub fn body(mut self, body: Option<Vec<u8>>) -> Self {
-        self.body = body;
-        if let Some(body) = &self.body {
-            self.content_length = Some(body.len());
+    pub fn body(mut self, body: impl Into<Body>) -> Self {
+        self.body = body.into();
+        self
+    }
+    pub fn location(self, location: impl Into<String>) -> Self {
+        self.header(Header::Location.to_str(), location)
+    }
+    /// Sets header `name`, replacing any value it already has. Use
+    /// [`ResponseBuilder::append_header`] for fields that can repeat, like
+    /// `Set-Cookie`.
+    ///
+    /// `Content-Length` and `Transfer-Encoding` are ignored, the server works
+    /// them out from the body. Invalid names or values are logged and dropped.
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        if Self::is_framing_header(name) {
+            return self;
+        }
+        if let Err(e) = self.headers.insert(name, &value.into()) {
+            error!("dropping response header: {}", e);
         }
         self
     }
-    pub fn content_type(mut self, content_type: ContentType) -> Self {
-        self.content_type = content_type;
+    /// Adds another value for header `name`, keeping existing ones.
+    #[allow(dead_code)]
+    pub fn append_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        if Self::is_framing_header(name) {
+            return self;
+        }
+        if let Err(e) = self.headers.append(name, &value.into()) {
+            error!("dropping response header: {}", e);
+        }
         self
     }
-    pub fn build(&mut self) -> Response {
-        if let Some(body) = &self.body {
-            if let Some(accept_encoding) = &self.accept_encoding {
-                match accept_encoding {
-                    Encoding::Gzip => {
-                        let mut encoder =
-                            GzEncoder::new(Vec::new(), flate2::Compression::default());
-                        encoder.write_all(body).expect("unable to compress body");
-                        self.body = Some(encoder.finish().expect("unable to finish compression"));
-                        self.content_length = Some(self.body.as_ref().unwrap().len());
-                    }
-                }
-            }
+    /// Appends every `(name, value)` pair, see [`ResponseBuilder::append_header`].
+    #[allow(dead_code)]
+    pub fn headers<I, K, V>(self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: Into<String>,
+    {
+        headers.into_iter().fold(self, |builder, (name, value)| {
+            builder.append_header(name.as_ref(), value)
+        })
+    }
+    fn is_framing_header(name: &str) -> bool {
+        let framing = [Header::ContentLength, Header::TransferEncoding];
+        if framing
+            .iter()
+            .any(|h| name.eq_ignore_ascii_case(h.to_str()))
+        {
+            debug!(
+                "ignoring response header {}, framing is set by the server",
+                name
+            );
+            return true;
+        }
+        false
+    }
+    /// Announces trailer fields `names` and sends the values received on
+    /// `trailers` after the body. Trailers can only be sent with a chunked
+    /// body, i.e. a stream of unknown length going to an HTTP/1.1 client, and
+    /// are dropped otherwise.
+    #[allow(dead_code)]
+    pub fn trailers(mut self, names: &[&str], trailers: Trailers) -> Self {
+        self.trailer_names = names.iter().map(|name| name.to_string()).collect_vec();
+        self.trailers = Some(trailers);
+        self
+    }
+    pub fn content_type(self, content_type: ContentType) -> Self {
+        self.header(Header::ContentType.to_str(), content_type.to_string())
+    }
+    /// Validators let clients revalidate their cached copy with
+    /// `If-None-Match` or `If-Modified-Since`, the server answers
+    /// `304 Not Modified` when it's still current.
+    pub fn etag(self, etag: &ETag) -> Self {
+        self.header(Header::ETag.to_str(), etag.to_string())
+    }
+    pub fn last_modified(self, last_modified: SystemTime) -> Self {
+        self.header(Header::LastModified.to_str(), date::format(last_modified))
+    }
+    /// Responses without a `Content-Type` are sent as `text/plain`.
+    pub fn build(mut self) -> Response {
+        if !self.headers.contains_key(Header::ContentType.to_str()) {
+            self = self.content_type(ContentType::PLAIN);
         }
         Response {
             status_code: self.status_code,
-            body: self.body.clone(),
-            content_length: self.content_length,
-            content_type: self.content_type,
-            accept_encoding: self.accept_encoding.clone(),
+            body: self.body,
+            headers: self.headers,
+            trailer_names: self.trailer_names,
+            trailers: self.trailers,
+            head_only: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    async fn serialize(response: Response, version: Version) -> String {
+        let mut output = vec![];
+        response.write_to(&mut output, version).await.unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[tokio::test]
+    async fn builds_headers() {
+        let response = Response::builder()
+            .status_code(StatusCode::Created)
+            .location("/old")
+            .header("Location", "/new")
+            .append_header("Set-Cookie", "a=1")
+            .headers([
+                ("Set-Cookie", "b=2"),
+                ("Content-Length", "99"),
+                ("Content-Type", "application/json"),
+            ])
+            .body(b"{}".to_vec())
+            .build();
+        assert_eq!(
+            serialize(response, Version::Http11).await,
+            "HTTP/1.1 201 Created\r\n\
+             Location: /new\r\n\
+             Set-Cookie: a=1\r\n\
+             Set-Cookie: b=2\r\n\
+             Content-Type: application/json\r\n\
+             content-length: 2\r\n\
+             \r\n\
+             {}"
+        );
+    }
+
+    #[tokio::test]
+    async fn defaults_to_plain_text() {
+        let response = Response::builder().body(b"hi".to_vec()).build();
+        assert_eq!(
+            serialize(response, Version::Http11).await,
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 2\r\n\r\nhi"
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_bodies_of_204_and_304() {
+        for status_code in [StatusCode::NoContent, StatusCode::NotModified] {
+            for body in [Body::from(b"ignored".to_vec()), two_piece_stream()] {
+                let response = Response::builder()
+                    .status_code(status_code)
+                    .body(body)
+                    .build();
+                assert_eq!(
+                    serialize(response, Version::Http11).await,
+                    format!(
+                        "HTTP/1.1 {}\r\ncontent-type: text/plain\r\n\r\n",
+                        status_code.status_line()
+                    )
+                );
+            }
+        }
+    }
+
+    /// "hello" and " world!" in two reads.
+    fn two_piece_stream() -> Body {
+        Body::stream(std::io::Cursor::new("hello").chain(std::io::Cursor::new(" world!")))
+    }
+
+    #[tokio::test]
+    async fn sends_streams_chunked_with_declared_trailers() {
+        let (sender, trailers) = oneshot::channel();
+        let mut values = HeaderMap::new();
+        values.append("X-Checksum", "abc").unwrap();
+        values.append("X-Undeclared", "secret").unwrap();
+        sender.send(values).unwrap();
+        let response = Response::builder()
+            .body(two_piece_stream())
+            .trailers(&["X-Checksum"], trailers)
+            .build();
+        assert_eq!(
+            serialize(response, Version::Http11).await,
+            "HTTP/1.1 200 OK\r\n\
+             content-type: text/plain\r\n\
+             transfer-encoding: chunked\r\n\
+             trailer: X-Checksum\r\n\
+             \r\n\
+             5\r\nhello\r\n\
+             7\r\n world!\r\n\
+             0\r\n\
+             X-Checksum: abc\r\n\
+             \r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn delimits_streams_by_closing_for_http_1_0() {
+        let (sender, trailers) = oneshot::channel();
+        sender.send(HeaderMap::new()).unwrap();
+        let response = Response::builder()
+            .body(two_piece_stream())
+            .trailers(&["X-Checksum"], trailers)
+            .build();
+        assert_eq!(
+            serialize(response, Version::Http10).await,
+            "HTTP/1.1 200 OK\r\n\
+             content-type: text/plain\r\n\
+             connection: close\r\n\
+             \r\n\
+             hello world!"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_sized_streams_with_content_length() {
+        let response = Response::builder()
+            .body(Body::sized_stream(std::io::Cursor::new("hello"), 5))
+            .build();
+        assert_eq!(
+            serialize(response, Version::Http11).await,
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 5\r\n\r\nhello"
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use super::status_code::StatusCode;
+
+/// How symlinks found while resolving a path below the root are treated.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Follow symlinks wherever they point, even outside of the root.
+    #[allow(dead_code)]
+    Follow,
+    /// Follow symlinks as long as their target stays inside the root.
+    #[default]
+    WithinRoot,
+    /// Refuse any path that goes through a symlink.
+    #[allow(dead_code)]
+    Deny,
+}
+
+#[derive(Error, Debug)]
+pub enum SafePathError {
+    #[error("file name is empty")]
+    Empty,
+    #[error("file name contains a NUL byte")]
+    NulByte,
+    #[error("file name must be a relative path")]
+    Absolute,
+    #[error("file name escapes the root directory")]
+    Traversal,
+    #[error("symlink {0:?} is not allowed")]
+    Symlink(PathBuf),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl SafePathError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            SafePathError::Empty | SafePathError::NulByte | SafePathError::Absolute => {
+                StatusCode::BadRequest
+            }
+            SafePathError::Traversal | SafePathError::Symlink(_) => StatusCode::Forbidden,
+            SafePathError::Io(e) => StatusCode::from(e),
+        }
+    }
+}
+
+/// Joins an untrusted, already percent-decoded `name` onto `root` and makes
+/// sure the result can't point outside of `root`.
+///
+/// The target itself doesn't have to exist (uploads), but `root` does. Every
+/// existing component on the way is checked against `symlinks`.
+pub fn safe_join(
+    root: &Path,
+    name: &str,
+    symlinks: SymlinkPolicy,
+) -> Result<PathBuf, SafePathError> {
+    if name.is_empty() {
+        return Err(SafePathError::Empty);
+    }
+    if name.contains('\0') {
+        return Err(SafePathError::NulByte);
+    }
+    let root = root.canonicalize()?;
+    let mut path = root.clone();
+    // Set once a component doesn't exist, the rest gets created by the caller
+    let mut missing = false;
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(SafePathError::Traversal),
+            Component::RootDir | Component::Prefix(_) => return Err(SafePathError::Absolute),
+        }
+        if missing {
+            continue;
+        }
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                missing = true;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !metadata.file_type().is_symlink() {
+            continue;
+        }
+        match symlinks {
+            SymlinkPolicy::Follow => {}
+            SymlinkPolicy::Deny => return Err(SafePathError::Symlink(path)),
+            SymlinkPolicy::WithinRoot => match path.canonicalize() {
+                Ok(target) if target.starts_with(&root) => {}
+                Ok(_) => return Err(SafePathError::Symlink(path)),
+                // Dangling symlink, writing through it would create a file
+                // wherever it points to
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    return Err(SafePathError::Symlink(path))
+                }
+                Err(e) => return Err(e.into()),
+            },
+        }
+    }
+    if path == root {
+        return Err(SafePathError::Empty);
+    }
+    Ok(path)
+}
+
+// Symlinks are created with the unix API
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::http::{path::percent_decode, temp_dir::TempDir};
+
+    /// `root/` with `inside.txt`, `link_in` pointing at it, `link_out` and
+    /// `dir_out` pointing outside of the root and a dangling `link_gone`.
+    fn tree(name: &str) -> (TempDir, PathBuf) {
+        let tmp = TempDir::new(&format!("safe-path-{}", name));
+        let root = tmp.0.join("root");
+        let outside = tmp.0.join("outside");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(root.join("inside.txt"), "in").unwrap();
+        fs::write(outside.join("secret.txt"), "out").unwrap();
+        symlink(root.join("inside.txt"), root.join("link_in")).unwrap();
+        symlink(outside.join("secret.txt"), root.join("link_out")).unwrap();
+        symlink(&outside, root.join("dir_out")).unwrap();
+        symlink(root.join("missing"), root.join("link_gone")).unwrap();
+        (tmp, root)
+    }
+
+    fn join(root: &Path, name: &str, symlinks: SymlinkPolicy) -> Result<PathBuf, SafePathError> {
+        safe_join(root, name, symlinks)
+    }
+
+    #[test]
+    fn joins_names_inside_the_root() {
+        let (_tmp, root) = tree("inside");
+        let root_canonical = root.canonicalize().unwrap();
+        let path = join(&root, "inside.txt", SymlinkPolicy::default()).unwrap();
+        assert_eq!(path, root_canonical.join("inside.txt"));
+        // Missing directories are kept for the caller to create
+        let path = join(&root, "new/dir/file.txt", SymlinkPolicy::default()).unwrap();
+        assert_eq!(path, root_canonical.join("new/dir/file.txt"));
+        let path = join(&root, "./inside.txt", SymlinkPolicy::default()).unwrap();
+        assert_eq!(path, root_canonical.join("inside.txt"));
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let (_tmp, root) = tree("traversal");
+        for name in ["..", "../outside/secret.txt", "a/../../x", "a/.."] {
+            assert!(
+                matches!(
+                    join(&root, name, SymlinkPolicy::Follow),
+                    Err(SafePathError::Traversal)
+                ),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_encoded_traversal() {
+        let (_tmp, root) = tree("encoded");
+        for encoded in [
+            "%2e%2e",
+            "%2E%2E/secret.txt",
+            "..%2F..%2Fetc",
+            "%2e%2e%2foutside",
+        ] {
+            let name = percent_decode(encoded).unwrap();
+            assert!(
+                matches!(
+                    join(&root, &name, SymlinkPolicy::Follow),
+                    Err(SafePathError::Traversal)
+                ),
+                "{:?}",
+                encoded
+            );
+        }
+        // Decoded once only, `%2e` is then a literal name inside the root
+        let name = percent_decode("%252e%252e").unwrap();
+        let path = join(&root, &name, SymlinkPolicy::default()).unwrap();
+        assert_eq!(path, root.canonicalize().unwrap().join("%2e%2e"));
+    }
+
+    #[test]
+    fn rejects_absolute_empty_and_nul() {
+        let (_tmp, root) = tree("invalid");
+        let policy = SymlinkPolicy::default();
+        assert!(matches!(
+            join(&root, "/etc/passwd", policy),
+            Err(SafePathError::Absolute)
+        ));
+        assert!(matches!(join(&root, "", policy), Err(SafePathError::Empty)));
+        assert!(matches!(
+            join(&root, ".", policy),
+            Err(SafePathError::Empty)
+        ));
+        assert!(matches!(
+            join(&root, "inside.txt\0.png", policy),
+            Err(SafePathError::NulByte)
+        ));
+    }
+
+    #[test]
+    fn follow_allows_every_symlink() {
+        let (_tmp, root) = tree("follow");
+        for name in ["link_in", "link_out", "dir_out/secret.txt", "link_gone"] {
+            assert!(
+                join(&root, name, SymlinkPolicy::Follow).is_ok(),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn within_root_allows_only_symlinks_into_the_root() {
+        let (_tmp, root) = tree("within");
+        assert!(join(&root, "link_in", SymlinkPolicy::WithinRoot).is_ok());
+        for name in [
+            "link_out",
+            "dir_out/secret.txt",
+            "dir_out/new.txt",
+            "link_gone",
+        ] {
+            assert!(
+                matches!(
+                    join(&root, name, SymlinkPolicy::WithinRoot),
+                    Err(SafePathError::Symlink(_))
+                ),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn deny_rejects_every_symlink() {
+        let (_tmp, root) = tree("deny");
+        assert!(join(&root, "inside.txt", SymlinkPolicy::Deny).is_ok());
+        for name in ["link_in", "link_out", "dir_out/secret.txt", "link_gone"] {
+            assert!(
+                matches!(
+                    join(&root, name, SymlinkPolicy::Deny),
+                    Err(SafePathError::Symlink(_))
+                ),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn maps_errors_to_status_codes() {
+        assert_eq!(
+            SafePathError::Traversal.status_code(),
+            StatusCode::Forbidden
+        );
+        assert_eq!(SafePathError::NulByte.status_code(), StatusCode::BadRequest);
+        assert_eq!(
+            SafePathError::Io(std::io::ErrorKind::NotFound.into()).status_code(),
+            StatusCode::NotFound
+        );
+    }
+}
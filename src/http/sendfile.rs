@@ -0,0 +1,54 @@
+use std::{io::ErrorKind, os::fd::AsRawFd};
+
+use tokio::{fs::File, io::Interest, net::tcp::OwnedWriteHalf};
+use tracing::debug;
+
+use super::body::Body;
+
+/// Most a single sendfile(2) call transfers on Linux.
+const MAX_SENDFILE: u64 = 0x7fff_f000;
+
+/// Sends `len` bytes of `file`, starting at `offset`, with sendfile(2): the
+/// kernel moves them from the page cache to the socket without copying them
+/// through userspace. Falls back to a buffered copy where the file system
+/// doesn't support it.
+pub async fn send_file(
+    writer: &mut OwnedWriteHalf,
+    file: File,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<()> {
+    let stream = writer.as_ref();
+    let (socket_fd, file_fd) = (stream.as_raw_fd(), file.as_raw_fd());
+    let mut position =
+        libc::off_t::try_from(offset).map_err(|_| std::io::Error::from(ErrorKind::InvalidInput))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(MAX_SENDFILE) as usize;
+        let sent = stream
+            .async_io(Interest::WRITABLE, || {
+                // SAFETY: both descriptors are owned by values borrowed for the
+                // whole call, and `position` is a valid `off_t`
+                let sent = unsafe { libc::sendfile(socket_fd, file_fd, &mut position, count) };
+                match sent {
+                    -1 => Err(std::io::Error::last_os_error()),
+                    sent => Ok(sent as u64),
+                }
+            })
+            .await;
+        match sent {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(sent) => remaining -= sent,
+            // Nothing is sent yet, so the copy can start over
+            Err(e)
+                if remaining == len
+                    && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) =>
+            {
+                debug!("sendfile unsupported, copying instead. {:?}", e);
+                return Body::File { file, offset, len }.write_to(writer).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
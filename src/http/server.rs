@@ -1,29 +1,102 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
 
 use itertools::Itertools;
 use regex::Regex;
 use tokio::{
-    io::{AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
+    io::BufReader,
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::Mutex,
 };
 use tracing::{debug, error};
 
 use crate::http::method::Method;
 
-use super::{request::Request, response::Response, status_code::StatusCode, Parse};
+use super::{
+    conditional::Conditions,
+    content_type::ContentType,
+    date,
+    encoding::{CompressionLevel, CompressionOptions, DecompressionOptions, Encoding},
+    header::Header,
+    path::{self, PathOptions, TrailingSlash},
+    request::Request,
+    response::Response,
+    static_files::{self, StaticFiles},
+    status_code::StatusCode,
+    uploads::{self, Uploads},
+    version::Version,
+    Parse,
+};
+
+pub type RouteHandler =
+    Arc<dyn Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync>;
+type RouteMap = HashMap<ServerRoute, Route>;
+/// Runs after routing but before the handler, and before the request body is
+/// transferred. Returning a response short-circuits the handler, e.g. to
+/// reject an unauthorised upload without the client ever sending it.
+pub type Middleware = Arc<dyn Fn(&Request) -> Option<Response> + Send + Sync>;
+
+struct Route {
+    handler: RouteHandler,
+    /// Media types the handler can respond with, most preferred first.
+    /// Empty when it doesn't declare any.
+    produces: Vec<ContentType>,
+    /// Media ranges of request bodies the handler understands.
+    consumes: Vec<ContentType>,
+    /// Only tried once no regular route matched, for catch-all mounts.
+    fallback: bool,
+}
 
-pub type RouteHandler = fn(Request) -> Response;
-type RouteMap = HashMap<ServerRoute, RouteHandler>;
+impl Route {
+    fn new(handler: RouteHandler) -> Self {
+        Route {
+            handler,
+            produces: vec![],
+            consumes: vec![],
+            fallback: false,
+        }
+    }
+}
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 enum ServerRoute {
     Get(String),
     Post(String),
+    Put(String),
+    Delete(String),
+}
+
+struct ServerConfig {
+    path: PathOptions,
+    compression: CompressionOptions,
+    decompression: DecompressionOptions,
+    /// Largest request body handlers can read, see
+    /// [`HttpServerBuilder::max_body_size`].
+    max_body_size: u64,
+    middlewares: Vec<Middleware>,
+    /// Value of the `Server` header, not sent when `None`.
+    server: Option<String>,
+    /// Send file bodies with sendfile(2), see [`HttpServerBuilder::sendfile`].
+    sendfile: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            path: PathOptions::default(),
+            compression: CompressionOptions::default(),
+            decompression: DecompressionOptions::default(),
+            max_body_size: 64 * 1024 * 1024,
+            middlewares: vec![],
+            server: None,
+            sendfile: true,
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct HttpServer {
     routes: Arc<RouteMap>,
+    config: Arc<ServerConfig>,
 }
 
 impl HttpServer {
@@ -39,8 +112,9 @@ impl HttpServer {
             match listener.accept().await {
                 Ok((stream, _)) => {
                     let routes = Arc::clone(&self.routes);
+                    let config = Arc::clone(&self.config);
                     tokio::spawn(async move {
-                        Self::handle_request(stream, routes).await;
+                        Self::handle_request(stream, routes, config).await;
                     });
                 }
                 Err(e) => error!("error while accepting client connection. {:?}", e),
@@ -48,61 +122,210 @@ impl HttpServer {
         }
     }
 
-    async fn handle_request(mut stream: TcpStream, routes: Arc<RouteMap>) {
-        let (r, mut writer) = stream.split();
+    async fn handle_request(stream: TcpStream, routes: Arc<RouteMap>, config: Arc<ServerConfig>) {
+        let (r, writer) = stream.into_split();
+        let writer = Arc::new(Mutex::new(writer));
         let mut reader = BufReader::new(r);
-        let mut request = Request::parse(&mut reader).await;
-        let route_method = find_matching_route_method(&routes, &request);
-        debug!("Received: {:?}", route_method);
-        match route_method {
-            None => {
+        let mut request = match Request::parse(&mut reader).await {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Unable to parse request: {}", e);
+                let response = Response::builder().status_code(e.status_code()).build();
+                return write_response(&writer, response, Version::Http11, &config).await;
+            }
+        };
+        let version = request.http_version;
+        let path = match path::normalize(&request.path, &config.path) {
+            Ok(path) => path,
+            Err(e) => {
+                debug!("Rejecting request path {:?}: {}", request.path, e);
                 let response = Response::builder()
-                    .status_code(StatusCode::NotFound)
+                    .status_code(StatusCode::BadRequest)
                     .build();
-                writer
-                    .write_all(&response.as_byte())
-                    .await
-                    .expect("unable to write HTTP response");
+                return write_response(&writer, response, version, &config).await;
+            }
+        };
+        if config.path.redirect && path != request.path {
+            let location = match &request.query {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+            let response = Response::builder()
+                .status_code(StatusCode::PermanentRedirect)
+                .location(location)
+                .build();
+            return write_response(&writer, response, version, &config).await;
+        }
+        request.path = path;
+        let expect_continue = match request.headers.get(Header::Expect.to_str()) {
+            None => false,
+            // HTTP/1.0 clients don't know about expectations (RFC 9110 section 10.1.1)
+            Some(_) if version == Version::Http10 => false,
+            Some(expect) if expect.eq_ignore_ascii_case("100-continue") => true,
+            Some(expect) => {
+                debug!("Unsupported expectation: {}", expect);
+                let response = Response::builder()
+                    .status_code(StatusCode::ExpectationFailed)
+                    .build();
+                return write_response(&writer, response, version, &config).await;
+            }
+        };
+        if request
+            .body
+            .len()
+            .is_some_and(|length| length > config.max_body_size)
+        {
+            let response = Response::builder()
+                .status_code(StatusCode::ContentTooLarge)
+                .build();
+            return write_response(&writer, response, version, &config).await;
+        }
+        if config.decompression.enabled {
+            if let Some(content_encoding) =
+                request.headers.get_joined(Header::ContentEncoding.to_str())
+            {
+                if let Err(e) = request
+                    .body
+                    .decode(&content_encoding, &config.decompression)
+                {
+                    debug!("Unable to decode request body: {}", e);
+                    // Tell the client which codings it can use instead
+                    // (RFC 9110 section 15.5.16)
+                    let supported = Encoding::ALL
+                        .iter()
+                        .map(|encoding| encoding.to_str())
+                        .join(", ");
+                    let response = Response::builder()
+                        .status_code(e.status_code())
+                        .header(Header::AcceptEncoding.to_str(), supported)
+                        .build();
+                    return write_response(&writer, response, version, &config).await;
+                }
             }
-            Some((route, route_params)) => {
+        }
+        request.body.attach(Box::new(reader), config.max_body_size);
+        if expect_continue {
+            request.body.expect_continue(writer.clone());
+        }
+
+        let accept_encoding = request.headers.get_joined(Header::AcceptEncoding.to_str());
+        let conditions = Conditions::from_request(&request);
+        let head = matches!(request.method, Method::Head);
+        let route_method = find_matching_route_method(&routes, &request);
+        debug!("Received: {:?}", route_method);
+        let mut response = match route_method {
+            None => Response::builder()
+                .status_code(StatusCode::NotFound)
+                .build(),
+            Some(Err(e)) => {
+                debug!("Rejecting route parameter: {}", e);
+                Response::builder()
+                    .status_code(StatusCode::BadRequest)
+                    .build()
+            }
+            Some(Ok((route, route_params))) => {
                 request.params = route_params;
-                let handler = routes
+                let route = routes
                     .get(route)
                     .expect("route should be available. This was already checked");
-                let response = handler(request);
-                writer
-                    .write_all(&response.as_byte())
-                    .await
-                    .expect("unable to write HTTP response");
+                let mut response = match negotiate_route(route, &mut request).or_else(|| {
+                    config
+                        .middlewares
+                        .iter()
+                        .find_map(|middleware| middleware(&request))
+                }) {
+                    Some(response) => response,
+                    None => (route.handler)(request).await,
+                };
+                if route.produces.len() > 1 {
+                    response.vary(Header::Accept.to_str());
+                }
+                response
+            }
+        };
+        let encoding = response.negotiate_encoding(accept_encoding.as_deref(), &config.compression);
+        let mut response = response.revalidate(&conditions);
+        if head {
+            response.omit_body();
+        }
+        let response = response.encode(encoding, config.compression.level);
+        write_response(&writer, response, version, &config).await;
+    }
+}
+
+/// Adds the headers every response carries, then sends it.
+async fn write_response(
+    writer: &Mutex<OwnedWriteHalf>,
+    mut response: Response,
+    version: Version,
+    config: &ServerConfig,
+) {
+    // Origin servers with a clock must send `Date` (RFC 9110 section 6.6.1)
+    response.default_header(Header::Date.to_str(), &date::now());
+    if let Some(server) = &config.server {
+        response.default_header(Header::Server.to_str(), server);
+    }
+    let mut writer = writer.lock().await;
+    if let Err(e) = response.send(&mut writer, version, config.sendfile).await {
+        error!("unable to write HTTP response. {:?}", e);
+    }
+}
+
+/// Checks the request against the media types `route` declares, answering
+/// `415 Unsupported Media Type` or `406 Not Acceptable` when they don't fit.
+/// Stores the negotiated response type in the request otherwise.
+fn negotiate_route(route: &Route, request: &mut Request) -> Option<Response> {
+    if !route.consumes.is_empty() && request.body.len() != Some(0) {
+        let supported = request.content_type().is_some_and(|content_type| {
+            route
+                .consumes
+                .iter()
+                .any(|range| range.includes(&content_type))
+        });
+        if !supported {
+            debug!("Unsupported request content type");
+            return Some(
+                Response::builder()
+                    .status_code(StatusCode::UnsupportedMediaType)
+                    .build(),
+            );
+        }
+    }
+    if !route.produces.is_empty() {
+        match request.negotiate_content_type(&route.produces) {
+            Ok(content_type) => request.response_type = Some(content_type),
+            Err(status_code) => {
+                return Some(Response::builder().status_code(status_code).build());
             }
         }
     }
+    None
 }
 
+type RouteMatch<'a> = Result<(&'a ServerRoute, HashMap<String, String>), path::PathError>;
+
 fn find_matching_route_method<'a>(
     routes: &'a Arc<RouteMap>,
     request: &Request,
-) -> Option<(&'a ServerRoute, HashMap<String, String>)> {
+) -> Option<RouteMatch<'a>> {
     let route_method = routes
         .iter()
-        .map(|(routes, _)| routes)
+        .sorted_by_key(|(_, route)| route.fallback)
+        .map(|(route, _)| route)
         // Match with only those routes that match RequestMethod
         .filter(|routes| match request.method {
-            Method::Get => match routes {
-                ServerRoute::Get(_) => true,
-                _ => false,
-            },
-            Method::Post => match routes {
-                ServerRoute::Post(_) => true,
-                _ => false,
-            },
-            _ => unimplemented!(),
+            Method::Get | Method::Head => matches!(routes, ServerRoute::Get(_)),
+            Method::Post => matches!(routes, ServerRoute::Post(_)),
+            Method::Put => matches!(routes, ServerRoute::Put(_)),
+            Method::Delete => matches!(routes, ServerRoute::Delete(_)),
         })
         // Use Regex to find route parameteres
         .find_map(|route| {
             let path = match route {
                 ServerRoute::Get(p) => p,
                 ServerRoute::Post(p) => p,
+                ServerRoute::Put(p) => p,
+                ServerRoute::Delete(p) => p,
             };
             let mut grp_names = vec![];
             let path_regex = path
@@ -115,7 +338,7 @@ fn find_matching_route_method<'a>(
                     grp_names.push(grp_name.clone());
                     // This might be better regex but test sends `/` in the path
                     //  "(?<%s>[\\w]*[^\\/])"
-                    return format!(r"(?<{}>.*)", grp_name);
+                    format!(r"(?<{}>.*)", grp_name)
                 })
                 .join("\\/");
 
@@ -128,43 +351,1119 @@ fn find_matching_route_method<'a>(
                 // );
                 return None;
             };
+            // Params are matched against the still-encoded path, so an encoded
+            // `/` (`%2F`) can't split a segment. Decode them only now.
             let mut route_params = HashMap::new();
-            grp_names.iter().for_each(|grp_name| {
-                let value = caps.name(grp_name).unwrap().as_str().to_string();
+            for grp_name in grp_names.iter() {
+                let value = match path::percent_decode(caps.name(grp_name).unwrap().as_str()) {
+                    Ok(value) => value,
+                    Err(e) => return Some(Err(e)),
+                };
                 route_params.insert(grp_name.clone(), value);
-            });
+            }
             // debug!(
             //     "Path: {:?}, Request Path: {:?}, Match: {:?}, Params: {:?}",
             //     path, request.path, true, route_params
             // );
-            return Some((route, route_params));
+            Some(Ok((route, route_params)))
         });
     route_method
 }
 
+fn route_handler<F, Fut>(handler: F) -> RouteHandler
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    Arc::new(move |request| Box::pin(handler(request)))
+}
+
 #[derive(Default)]
 pub struct HttpServerBuilder {
     routes: RouteMap,
+    /// Route registered last, the one [`HttpServerBuilder::produces`] and
+    /// [`HttpServerBuilder::consumes`] apply to.
+    last_route: Option<ServerRoute>,
+    config: ServerConfig,
 }
 
 impl HttpServerBuilder {
     pub fn new() -> Self {
         Self::default()
     }
-    pub fn get(mut self, path: &str, handler: RouteHandler) -> Self {
-        self.routes.insert(ServerRoute::Get(path.into()), handler);
+    pub fn get<F, Fut>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.route(ServerRoute::Get(path.into()), route_handler(handler))
+    }
+    pub fn post<F, Fut>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.route(ServerRoute::Post(path.into()), route_handler(handler))
+    }
+    pub fn put<F, Fut>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.route(ServerRoute::Put(path.into()), route_handler(handler))
+    }
+    pub fn delete<F, Fut>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.route(ServerRoute::Delete(path.into()), route_handler(handler))
+    }
+    fn route(mut self, route: ServerRoute, handler: RouteHandler) -> Self {
+        self.routes.insert(route.clone(), Route::new(handler));
+        self.last_route = Some(route);
         self
     }
-    pub fn post(mut self, path: &str, handler: RouteHandler) -> Self {
-        self.routes.insert(ServerRoute::Post(path.into()), handler);
+    /// Serves `files` below `prefix`: `/assets/css/site.css` maps to
+    /// `css/site.css` in the directory. Regular routes take precedence over
+    /// the mount, so it can sit at `/`.
+    ///
+    /// Directories are told apart from files by their trailing slash, so
+    /// this needs the default [`TrailingSlash::Keep`].
+    pub fn static_files(mut self, prefix: &str, files: StaticFiles) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        let files = Arc::new(files);
+        let handler = route_handler(move |request| {
+            let files = Arc::clone(&files);
+            async move { files.serve(request).await }
+        });
+        let route = ServerRoute::Get(format!("{}/:{}", prefix, static_files::PATH_PARAM));
+        let mut mount = Route::new(handler);
+        mount.fallback = true;
+        self.routes.insert(route.clone(), mount);
+        self.last_route = Some(route);
+        if !prefix.is_empty() {
+            // `/assets` itself, redirected to `/assets/`
+            let location = format!("{}/", prefix);
+            let handler = route_handler(move |_| {
+                let location = location.clone();
+                async move {
+                    Response::builder()
+                        .status_code(StatusCode::MovedPermanently)
+                        .location(location)
+                        .build()
+                }
+            });
+            self.routes
+                .insert(ServerRoute::Get(prefix.to_string()), Route::new(handler));
+        }
+        self
+    }
+    /// Accepts `POST` and `PUT` uploads of files below `prefix`, and their
+    /// `DELETE`, in the directory of `uploads`. Can share the prefix with
+    /// [`HttpServerBuilder::static_files`] to serve them back.
+    pub fn uploads(self, prefix: &str, uploads: Uploads) -> Self {
+        let route = format!("{}/:{}", prefix.trim_end_matches('/'), uploads::FILE_PARAM);
+        let uploads = Arc::new(uploads);
+        let (post, put, delete) = (
+            Arc::clone(&uploads),
+            Arc::clone(&uploads),
+            Arc::clone(&uploads),
+        );
+        self.post(&route, move |request| {
+            let uploads = Arc::clone(&post);
+            async move { uploads.post(request).await }
+        })
+        .put(&route, move |request| {
+            let uploads = Arc::clone(&put);
+            async move { uploads.put(request).await }
+        })
+        .delete(&route, move |request| {
+            let uploads = Arc::clone(&delete);
+            async move { uploads.delete(request).await }
+        })
+    }
+    fn last_route(&mut self) -> &mut Route {
+        let route = self
+            .last_route
+            .as_ref()
+            .expect("register a route before declaring its media types");
+        self.routes
+            .get_mut(route)
+            .expect("last route should be registered")
+    }
+    /// Declares the media types the route registered last responds with,
+    /// most preferred first. Requests whose `Accept` allows none of them get
+    /// `406 Not Acceptable`, others find the pick in
+    /// [`Request::response_type`].
+    #[allow(dead_code)]
+    pub fn produces(mut self, content_types: &[ContentType]) -> Self {
+        self.last_route().produces = content_types.to_vec();
+        self
+    }
+    /// Declares the media ranges (`application/json`, `text/*`...) the route
+    /// registered last accepts in request bodies. Bodies of any other type
+    /// get `415 Unsupported Media Type`.
+    #[allow(dead_code)]
+    pub fn consumes(mut self, content_types: &[ContentType]) -> Self {
+        self.last_route().consumes = content_types.to_vec();
+        self
+    }
+    /// Largest request body accepted, 64 MiB by default. Requests announcing a
+    /// larger `content-length` are answered with `413 Payload Too Large`
+    /// before the handler runs, chunked bodies fail once they grow past it.
+    #[allow(dead_code)]
+    pub fn max_body_size(mut self, max_body_size: u64) -> Self {
+        self.config.max_body_size = max_body_size;
+        self
+    }
+    #[allow(dead_code)]
+    /// Collapse `//` in request paths before routing. Enabled by default.
+    pub fn merge_slashes(mut self, merge_slashes: bool) -> Self {
+        self.config.path.merge_slashes = merge_slashes;
+        self
+    }
+    /// What to do with a trailing slash before routing. Only
+    /// [`TrailingSlash::Keep`] works with [`HttpServerBuilder::static_files`].
+    #[allow(dead_code)]
+    pub fn trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.config.path.trailing_slash = trailing_slash;
+        self
+    }
+    #[allow(dead_code)]
+    /// Redirect requests for non-canonical paths (`/echo/./abc`) to their
+    /// canonical form instead of routing them directly.
+    pub fn redirect_to_canonical(mut self, redirect: bool) -> Self {
+        self.config.path.redirect = redirect;
+        self
+    }
+    /// Registers a [`Middleware`]. They run in registration order, the first
+    /// one returning a response wins.
+    #[allow(dead_code)]
+    pub fn middleware<F>(mut self, middleware: F) -> Self
+    where
+        F: Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    {
+        self.config.middlewares.push(Arc::new(middleware));
+        self
+    }
+    /// Compress responses with the best coding the client accepts. Enabled
+    /// by default.
+    #[allow(dead_code)]
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.config.compression.enabled = enabled;
+        self
+    }
+    /// Smallest body worth compressing, 1 KiB by default.
+    pub fn compression_min_size(mut self, min_size: u64) -> Self {
+        self.config.compression.min_size = min_size;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn compression_level(mut self, level: CompressionLevel) -> Self {
+        self.config.compression.level = level;
+        self
+    }
+    /// Decompress request bodies sent with a `Content-Encoding` before
+    /// handlers read them. Disabled by default, bodies are then handed over
+    /// as sent. Unsupported codings are refused with `415 Unsupported Media
+    /// Type`.
+    pub fn decompress_requests(mut self, enabled: bool) -> Self {
+        self.config.decompression.enabled = enabled;
+        self
+    }
+    /// Largest decompressed request body, 64 MiB by default.
+    #[allow(dead_code)]
+    pub fn max_decompressed_size(mut self, max_size: u64) -> Self {
+        self.config.decompression.max_size = max_size;
+        self
+    }
+    /// Largest decompressed to compressed ratio of a request body, 100 by
+    /// default. Guards against decompression bombs.
+    #[allow(dead_code)]
+    pub fn max_decompression_ratio(mut self, max_ratio: u64) -> Self {
+        self.config.decompression.max_ratio = max_ratio;
+        self
+    }
+    /// Sends `Server: <server>` with every response that doesn't set its own.
+    #[allow(dead_code)]
+    pub fn server_header(mut self, server: impl Into<String>) -> Self {
+        self.config.server = Some(server.into());
+        self
+    }
+    /// Hand file bodies that go out uncompressed to the kernel with
+    /// sendfile(2) rather than copying them through userspace. On by default,
+    /// only has an effect on Linux.
+    pub fn sendfile(mut self, sendfile: bool) -> Self {
+        self.config.sendfile = sendfile;
         self
     }
     pub fn build(self) -> HttpServer {
+        // Stripping would redirect `dir/` to itself forever, appending
+        // would turn every file into a directory that doesn't exist
+        assert!(
+            self.config.path.trailing_slash == TrailingSlash::Keep
+                || !self.routes.values().any(|route| route.fallback),
+            "static files need TrailingSlash::Keep"
+        );
         HttpServer {
             routes: Arc::new(self.routes),
+            config: Arc::new(self.config),
         }
     }
     pub async fn start(self) -> anyhow::Result<()> {
         self.build().start().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use std::{fs, os::unix::fs::symlink};
+
+    use super::{
+        super::{body::Body, etag::ETag, safe_path::SymlinkPolicy, temp_dir::TempDir},
+        *,
+    };
+
+    /// Sends raw `request` to `server` over a real connection and returns
+    /// everything the server writes back before closing it.
+    async fn exchange(server: &HttpServer, request: &str) -> String {
+        exchange_bytes(server, request.as_bytes()).await
+    }
+
+    async fn exchange_bytes(server: &HttpServer, request: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let routes = Arc::clone(&server.routes);
+        let config = Arc::clone(&server.config);
+        let handler = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            HttpServer::handle_request(stream, routes, config).await;
+        });
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        handler.await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    async fn get(server: &HttpServer, target: &str) -> String {
+        exchange(
+            server,
+            &format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target),
+        )
+        .await
+    }
+
+    fn status_line(response: &str) -> &str {
+        response.split("\r\n").next().unwrap_or_default()
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        response
+            .split("\r\n\r\n")
+            .next()?
+            .split("\r\n")
+            .skip(1)
+            .find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+    }
+
+    fn body(response: &str) -> &str {
+        response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+    }
+
+    async fn echo_path(request: Request) -> Response {
+        Response::builder().body(request.path.into_bytes()).build()
+    }
+
+    async fn tagged(_request: Request) -> Response {
+        let body = "tagged ".repeat(100);
+        Response::builder()
+            .etag(&ETag::from_content(body.as_bytes()))
+            .body(body.into_bytes())
+            .build()
+    }
+
+    #[tokio::test]
+    async fn not_modified_matches_the_encoded_response() {
+        let server = HttpServer::builder()
+            .compression_min_size(0)
+            .get("/tagged", tagged)
+            .build();
+        let request = |extra: &str| {
+            format!(
+                "GET /tagged HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n{}\r\n",
+                extra
+            )
+        };
+        let full = exchange(&server, &request("")).await;
+        assert_eq!(status_line(&full), "HTTP/1.1 200 OK");
+        assert_eq!(header(&full, "content-encoding"), Some("gzip"));
+        let etag = header(&full, "etag").unwrap();
+
+        let not_modified =
+            exchange(&server, &request(&format!("If-None-Match: {}\r\n", etag))).await;
+        assert_eq!(status_line(&not_modified), "HTTP/1.1 304 Not Modified");
+        assert_eq!(header(&not_modified, "etag"), Some(etag));
+        assert_eq!(header(&not_modified, "vary"), Some("accept-encoding"));
+        assert_eq!(body(&not_modified), "");
+    }
+
+    /// 4 KiB of text, unless `kind` asks for a small body or a type that's
+    /// compressed already.
+    async fn typed(request: Request) -> Response {
+        let (content_type, length) = match request.params["kind"].as_str() {
+            "small" => ("text/plain", 1023),
+            "png" => ("image/png", 4096),
+            "zip" => ("application/zip", 4096),
+            _ => ("text/plain", 4096),
+        };
+        Response::builder()
+            .content_type(content_type.parse().unwrap())
+            .body(vec![b'a'; length])
+            .build()
+    }
+
+    fn get_encoded(target: &str, accept_encoding: &str) -> String {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\n\r\n",
+            target, accept_encoding
+        )
+    }
+
+    #[tokio::test]
+    async fn compresses_only_what_is_worth_it() {
+        let server = HttpServer::builder().get("/typed/:kind", typed).build();
+        let response = exchange(&server, &get_encoded("/typed/text", "gzip")).await;
+        assert_eq!(header(&response, "content-encoding"), Some("gzip"));
+        assert_eq!(header(&response, "vary"), Some("accept-encoding"));
+
+        for (kind, length) in [("small", "1023"), ("png", "4096"), ("zip", "4096")] {
+            let response = exchange(
+                &server,
+                &get_encoded(&format!("/typed/{}", kind), "gzip, br"),
+            )
+            .await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK", "{}", kind);
+            assert_eq!(header(&response, "content-encoding"), None, "{}", kind);
+            assert_eq!(
+                header(&response, "content-length"),
+                Some(length),
+                "{}",
+                kind
+            );
+            // Still depends on `Accept-Encoding`, see below
+            assert_eq!(
+                header(&response, "vary"),
+                Some("accept-encoding"),
+                "{}",
+                kind
+            );
+        }
+        // Unless the client refuses them as they are
+        let response = exchange(&server, &get_encoded("/typed/small", "gzip, identity;q=0")).await;
+        assert_eq!(header(&response, "content-encoding"), Some("gzip"));
+    }
+
+    #[tokio::test]
+    async fn compression_can_be_disabled() {
+        let server = HttpServer::builder()
+            .compression(false)
+            .get("/typed/:kind", typed)
+            .build();
+        let response = exchange(&server, &get_encoded("/typed/text", "gzip, identity;q=0")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(header(&response, "content-encoding"), None);
+        assert_eq!(header(&response, "content-length"), Some("4096"));
+        assert_eq!(header(&response, "vary"), None);
+    }
+
+    async fn streamed(_request: Request) -> Response {
+        let body = "streamed ".repeat(1000);
+        let length = body.len() as u64;
+        Response::builder()
+            .body(Body::sized_stream(std::io::Cursor::new(body), length))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn head_sends_the_framing_of_get_without_a_body() {
+        let server = HttpServer::builder().get("/streamed", streamed).build();
+        let request = |method: &str, accept_encoding: &str| {
+            format!(
+                "{} /streamed HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\n\r\n",
+                method, accept_encoding
+            )
+        };
+
+        let identity = exchange(&server, &request("HEAD", "identity")).await;
+        assert_eq!(status_line(&identity), "HTTP/1.1 200 OK");
+        assert_eq!(header(&identity, "content-length"), Some("9000"));
+        assert_eq!(header(&identity, "content-encoding"), None);
+        assert_eq!(body(&identity), "");
+
+        let get = exchange(&server, &request("GET", "gzip")).await;
+        let head = exchange(&server, &request("HEAD", "gzip")).await;
+        for name in [
+            "content-encoding",
+            "transfer-encoding",
+            "content-length",
+            "vary",
+        ] {
+            assert_eq!(header(&head, name), header(&get, name), "{}", name);
+        }
+        assert_eq!(header(&head, "content-encoding"), Some("gzip"));
+        assert_eq!(body(&head), "");
+    }
+
+    #[tokio::test]
+    async fn routes_the_normalized_path() {
+        let server = HttpServer::builder().get("/a/b", echo_path).build();
+        let response = get(&server, "/a/./c/..//b").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(body(&response), "/a/b");
+    }
+
+    #[tokio::test]
+    async fn redirects_to_the_canonical_path_keeping_the_query() {
+        let server = HttpServer::builder()
+            .redirect_to_canonical(true)
+            .get("/a/b", echo_path)
+            .build();
+        let response = get(&server, "/a/./c/..//b?x=1&y=%20").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 308 Permanent Redirect");
+        assert_eq!(header(&response, "location"), Some("/a/b?x=1&y=%20"));
+        // Canonical paths are routed as usual
+        let response = get(&server, "/a/b?x=1").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn applies_trailing_slash_modes() {
+        for (mode, target, expected) in [
+            (TrailingSlash::Keep, "/a/", "/a/"),
+            (TrailingSlash::Keep, "/a", "/a"),
+            (TrailingSlash::Strip, "/a/", "/a"),
+            (TrailingSlash::Strip, "/a", "/a"),
+            (TrailingSlash::Append, "/a", "/a/"),
+            (TrailingSlash::Append, "/a/", "/a/"),
+        ] {
+            let server = HttpServer::builder()
+                .trailing_slash(mode)
+                .get("/a", echo_path)
+                .get("/a/", echo_path)
+                .build();
+            let response = get(&server, target).await;
+            assert_eq!(body(&response), expected, "{:?} {}", mode, target);
+
+            let server = HttpServer::builder()
+                .trailing_slash(mode)
+                .redirect_to_canonical(true)
+                .get("/a", echo_path)
+                .get("/a/", echo_path)
+                .build();
+            let response = get(&server, target).await;
+            match target == expected {
+                true => assert_eq!(body(&response), expected),
+                false => assert_eq!(header(&response, "location"), Some(expected)),
+            }
+        }
+    }
+
+    fn reject_uploads(request: &Request) -> Option<Response> {
+        matches!(request.method, Method::Post).then(|| {
+            Response::builder()
+                .status_code(StatusCode::Forbidden)
+                .build()
+        })
+    }
+
+    #[tokio::test]
+    async fn middleware_rejects_before_100_continue() {
+        let server = HttpServer::builder()
+            .middleware(reject_uploads)
+            .post("/upload", echo_path)
+            .build();
+        // Only the head, the client waits for `100 Continue` before the body
+        let response = exchange(
+            &server,
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 403 Forbidden");
+        assert!(!response.contains("100 Continue"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn middleware_can_hold_state() {
+        let token = "Bearer secret".to_string();
+        let server = HttpServer::builder()
+            .middleware(move |request| {
+                let authorization = request.headers.get("Authorization");
+                (authorization != Some(token.as_str())).then(|| {
+                    Response::builder()
+                        .status_code(StatusCode::Unauthorized)
+                        .build()
+                })
+            })
+            .get("/private", echo_path)
+            .build();
+        let request = |authorization: &str| {
+            format!(
+                "GET /private HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+                authorization
+            )
+        };
+        let response = exchange(&server, &request("")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 401 Unauthorized");
+        let response = exchange(&server, &request("Authorization: Bearer wrong\r\n")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 401 Unauthorized");
+        let response = exchange(&server, &request("Authorization: Bearer secret\r\n")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(body(&response), "/private");
+    }
+
+    async fn read_body(mut request: Request) -> Response {
+        match request.body.bytes().await {
+            Ok(body) => Response::builder().body(body).build(),
+            Err(e) => Response::builder().status_code(e.status_code()).build(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reading_the_body_sends_100_continue() {
+        let server = HttpServer::builder()
+            .middleware(reject_uploads)
+            .put("/upload", read_body)
+            .build();
+        let response = exchange(
+            &server,
+            "PUT /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\nhello",
+        )
+        .await;
+        let (interim, response) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(interim, "HTTP/1.1 100 Continue");
+        assert_eq!(status_line(response), "HTTP/1.1 200 OK");
+        assert_eq!(body(response), "hello");
+    }
+
+    async fn response_type(request: Request) -> Response {
+        let content_type = request
+            .response_type
+            .expect("negotiated before the handler");
+        Response::builder()
+            .content_type(content_type.clone())
+            .body(content_type.to_string().into_bytes())
+            .build()
+    }
+
+    #[tokio::test]
+    async fn negotiates_what_a_route_produces() {
+        let server = HttpServer::builder()
+            .get("/data", response_type)
+            .produces(&[ContentType::JSON, ContentType::HTML])
+            .build();
+        let request = |accept: &str| {
+            format!(
+                "GET /data HTTP/1.1\r\nHost: localhost\r\nAccept: {}\r\n\r\n",
+                accept
+            )
+        };
+
+        let response = exchange(&server, &request("text/html, application/json;q=0.5")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(body(&response), "text/html");
+        assert_eq!(header(&response, "vary"), Some("accept"));
+
+        let response = exchange(&server, &request("*/*")).await;
+        assert_eq!(body(&response), "application/json");
+
+        let response = exchange(&server, &request("text/plain, application/json;q=0")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 406 Not Acceptable");
+    }
+
+    #[tokio::test]
+    async fn refuses_bodies_a_route_does_not_consume() {
+        let server = HttpServer::builder()
+            .post("/data", read_body)
+            .consumes(&[ContentType::JSON, "text/*".parse().unwrap()])
+            .build();
+        let request = |content_type: &str, body: &str| {
+            format!(
+                "POST /data HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            )
+        };
+
+        for content_type in ["application/json", "text/csv; charset=utf-8"] {
+            let response = exchange(
+                &server,
+                &request(&format!("Content-Type: {}\r\n", content_type), "1,2"),
+            )
+            .await;
+            assert_eq!(
+                status_line(&response),
+                "HTTP/1.1 200 OK",
+                "{}",
+                content_type
+            );
+        }
+        for content_type in ["Content-Type: application/xml\r\n", ""] {
+            let response = exchange(&server, &request(content_type, "<a/>")).await;
+            assert_eq!(
+                status_line(&response),
+                "HTTP/1.1 415 Unsupported Media Type",
+                "{}",
+                content_type
+            );
+        }
+        // Without a body there is nothing to check
+        let response = exchange(&server, &request("", "")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+    }
+
+    fn post_encoded(encoding: Encoding, body: &[u8]) -> Vec<u8> {
+        let encoded = encoding.encode(body, CompressionLevel::Fastest).unwrap();
+        let mut request = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n",
+            encoding.to_str(),
+            encoded.len()
+        )
+        .into_bytes();
+        request.extend(encoded);
+        request
+    }
+
+    #[tokio::test]
+    async fn refuses_decompression_bombs() {
+        let server = HttpServer::builder()
+            .decompress_requests(true)
+            .max_decompressed_size(1024 * 1024)
+            // Only the size limit, not the ratio, is under test
+            .max_decompression_ratio(u64::MAX)
+            .post("/upload", read_body)
+            .build();
+        let zeros = vec![0; 32 * 1024 * 1024];
+        for encoding in Encoding::ALL {
+            let response = exchange_bytes(&server, &post_encoded(encoding, &zeros)).await;
+            assert_eq!(
+                status_line(&response),
+                "HTTP/1.1 413 Content Too Large",
+                "{:?}",
+                encoding
+            );
+        }
+        let text = "fits ".repeat(200_000);
+        for encoding in Encoding::ALL {
+            let response = exchange_bytes(&server, &post_encoded(encoding, text.as_bytes())).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK", "{:?}", encoding);
+            assert_eq!(body(&response), text, "{:?}", encoding);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_announced_bodies_over_the_limit_before_the_handler() {
+        static CALLS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let server = HttpServer::builder()
+            .max_body_size(10)
+            .post("/upload", |request| {
+                CALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                read_body(request)
+            })
+            .build();
+        let request = |length: usize| {
+            format!(
+                "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+                length,
+                "x".repeat(length)
+            )
+        };
+        let response = exchange(&server, &request(11)).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 413 Content Too Large");
+        assert_eq!(CALLS.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        let response = exchange(&server, &request(10)).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(CALLS.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_chunked_bodies_growing_over_the_limit() {
+        let server = HttpServer::builder()
+            .max_body_size(10)
+            .post("/upload", read_body)
+            .build();
+        let request = |chunks: &str| {
+            format!(
+                "POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{}0\r\n\r\n",
+                chunks
+            )
+        };
+        let response = exchange(&server, &request("6\r\nabcdef\r\n5\r\nghijk\r\n")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 413 Content Too Large");
+
+        let response = exchange(&server, &request("6\r\nabcdef\r\n4\r\nghij\r\n")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(body(&response), "abcdefghij");
+    }
+
+    #[test]
+    #[should_panic(expected = "static files need TrailingSlash::Keep")]
+    fn refuses_static_files_without_trailing_slashes() {
+        HttpServer::builder()
+            .static_files("/files", StaticFiles::new("."))
+            .trailing_slash(TrailingSlash::Strip)
+            .build();
+    }
+
+    /// `root/` with index files, dotfiles, files of a few types and symlinks
+    /// pointing inside and outside of it, served at `/static`.
+    fn site(name: &str, files: impl FnOnce(StaticFiles) -> StaticFiles) -> (TempDir, HttpServer) {
+        let tmp = TempDir::new(&format!("server-{}", name));
+        let root = tmp.0.join("root");
+        let outside = tmp.0.join("outside");
+        for dir in [&root.join("docs"), &root.join(".git"), &outside] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        for (path, content) in [
+            ("index.html", "home"),
+            ("docs/index.html", "docs"),
+            ("docs/.hidden", "hidden"),
+            ("file.txt", "text"),
+            ("style.css", "css"),
+            ("data.json", "{}"),
+            ("image.PNG", "png"),
+            ("archive.xyz", "xyz"),
+            (".env", "secret"),
+            (".git/config", "secret"),
+        ] {
+            fs::write(root.join(path), content).unwrap();
+        }
+        fs::write(outside.join("secret.txt"), "outside").unwrap();
+        symlink(root.join("file.txt"), root.join("link_in")).unwrap();
+        symlink(outside.join("secret.txt"), root.join("link_out")).unwrap();
+        let server = HttpServer::builder()
+            .static_files("/static", files(StaticFiles::new(&root)))
+            .build();
+        (tmp, server)
+    }
+
+    #[tokio::test]
+    async fn serves_index_files_of_directories() {
+        let (_tmp, server) = site("index", |files| files);
+        for (target, expected) in [("/static/", "home"), ("/static/docs/", "docs")] {
+            let response = get(&server, target).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK", "{}", target);
+            assert_eq!(
+                header(&response, "content-type"),
+                Some("text/html; charset=utf-8")
+            );
+            assert_eq!(body(&response), expected);
+        }
+        for (target, location) in [
+            ("/static", "/static/"),
+            ("/static/docs", "/static/docs/"),
+            ("/static/docs?lang=en", "/static/docs/?lang=en"),
+        ] {
+            let response = get(&server, target).await;
+            assert_eq!(
+                status_line(&response),
+                "HTTP/1.1 301 Moved Permanently",
+                "{}",
+                target
+            );
+            assert_eq!(header(&response, "location"), Some(location));
+        }
+        let (_tmp, server) = site("no-index", |files| files.index(None));
+        let response = get(&server, "/static/docs/").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn does_not_serve_files_as_directories() {
+        let (_tmp, server) = site("file-slash", |files| files);
+        let response = get(&server, "/static/file.txt").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(body(&response), "text");
+        let response = get(&server, "/static/file.txt/").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn hides_dotfiles() {
+        let (_tmp, server) = site("hidden", |files| files);
+        for target in [
+            "/static/.env",
+            "/static/.git/config",
+            "/static/docs/.hidden",
+        ] {
+            let response = get(&server, target).await;
+            assert_eq!(
+                status_line(&response),
+                "HTTP/1.1 404 Not Found",
+                "{}",
+                target
+            );
+        }
+        let (_tmp, server) = site("hidden-served", |files| files.serve_hidden(true));
+        let response = get(&server, "/static/.env").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(body(&response), "secret");
+    }
+
+    #[tokio::test]
+    async fn applies_the_symlink_policy() {
+        let cases = [
+            (SymlinkPolicy::WithinRoot, "200 OK", "403 Forbidden"),
+            (SymlinkPolicy::Follow, "200 OK", "200 OK"),
+            (SymlinkPolicy::Deny, "403 Forbidden", "403 Forbidden"),
+        ];
+        for (policy, link_in, link_out) in cases {
+            let name = format!("symlinks-{:?}", policy);
+            let (_tmp, server) = site(&name, |files| files.symlinks(policy));
+            let response = get(&server, "/static/link_in").await;
+            assert_eq!(status_line(&response), format!("HTTP/1.1 {}", link_in));
+            let response = get(&server, "/static/link_out").await;
+            assert_eq!(status_line(&response), format!("HTTP/1.1 {}", link_out));
+        }
+    }
+
+    /// Eight visible entries in the root, `link_out` and dotfiles are left out.
+    fn listed_site(name: &str) -> (TempDir, HttpServer) {
+        site(name, |files| {
+            files
+                .index(None)
+                .directory_listings(true)
+                .listing_page_size(3)
+        })
+    }
+
+    #[tokio::test]
+    async fn pages_directory_listings() {
+        let (_tmp, server) = listed_site("listing-pages");
+        for (page, status) in [
+            ("", "200 OK"),
+            ("?page=1", "200 OK"),
+            ("?page=3", "200 OK"),
+            ("?page=4", "404 Not Found"),
+            ("?page=0", "400 Bad Request"),
+            ("?page=-1", "400 Bad Request"),
+            ("?page=two", "400 Bad Request"),
+        ] {
+            let response = get(&server, &format!("/static/{}", page)).await;
+            assert_eq!(
+                status_line(&response),
+                format!("HTTP/1.1 {}", status),
+                "{}",
+                page
+            );
+        }
+        let first = body(&get(&server, "/static/").await).to_string();
+        assert!(first.contains("Page 1 of 3"), "{}", first);
+        assert!(first.contains("<a href=\"docs/\">docs/</a>"), "{}", first);
+        assert!(!first.contains(".env") && !first.contains("link_out"));
+        let last = get(&server, "/static/?page=3").await;
+        assert!(body(&last).contains("style.css"));
+        assert!(!body(&last).contains("docs/"));
+    }
+
+    #[tokio::test]
+    async fn picks_the_listing_format_from_accept() {
+        let (_tmp, server) = listed_site("listing-accept");
+        let request = |accept: &str| {
+            format!(
+                "GET /static/docs/ HTTP/1.1\r\nHost: localhost\r\nAccept: {}\r\n\r\n",
+                accept
+            )
+        };
+        for (accept, content_type) in [
+            ("*/*", "text/html; charset=utf-8"),
+            ("text/html", "text/html; charset=utf-8"),
+            ("application/json", "application/json; charset=utf-8"),
+            (
+                "text/html;q=0.5, application/json",
+                "application/json; charset=utf-8",
+            ),
+        ] {
+            let response = exchange(&server, &request(accept)).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK", "{}", accept);
+            assert_eq!(
+                header(&response, "content-type"),
+                Some(content_type),
+                "{}",
+                accept
+            );
+            assert_eq!(header(&response, "vary"), Some("accept"));
+        }
+        let json = exchange(&server, &request("application/json")).await;
+        assert!(
+            body(&json)
+                .starts_with("{\"path\":\"/static/docs/\",\"page\":1,\"pages\":1,\"total\":1,"),
+            "{}",
+            json
+        );
+        let response = exchange(&server, &request("image/png")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 406 Not Acceptable");
+    }
+
+    #[tokio::test]
+    async fn detects_media_types_from_extensions() {
+        let (_tmp, server) = site("mime", |files| files);
+        for (target, content_type) in [
+            ("/static/file.txt", "text/plain; charset=utf-8"),
+            ("/static/style.css", "text/css; charset=utf-8"),
+            ("/static/data.json", "application/json"),
+            ("/static/image.PNG", "image/png"),
+            ("/static/archive.xyz", "application/octet-stream"),
+        ] {
+            let response = get(&server, target).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK", "{}", target);
+            assert_eq!(
+                header(&response, "content-type"),
+                Some(content_type),
+                "{}",
+                target
+            );
+        }
+    }
+
+    fn upload_server(tmp: &TempDir, uploads: impl FnOnce(Uploads) -> Uploads) -> HttpServer {
+        HttpServer::builder()
+            .max_body_size(1024)
+            .uploads("/files", uploads(Uploads::new(&tmp.0)))
+            .build()
+    }
+
+    async fn send(server: &HttpServer, method: &str, target: &str, headers: &str) -> String {
+        exchange(
+            server,
+            &format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+                method, target, headers
+            ),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn put_tells_created_from_replaced() {
+        let tmp = TempDir::new("server-put");
+        let server = upload_server(&tmp, |uploads| uploads);
+        let created = send(
+            &server,
+            "PUT",
+            "/files/a.txt",
+            "Content-Length: 3\r\n\r\none",
+        )
+        .await;
+        assert_eq!(status_line(&created), "HTTP/1.1 201 Created");
+        let replaced = send(
+            &server,
+            "PUT",
+            "/files/a.txt",
+            "Content-Length: 3\r\n\r\ntwo",
+        )
+        .await;
+        assert_eq!(status_line(&replaced), "HTTP/1.1 204 No Content");
+        assert_ne!(header(&created, "etag"), header(&replaced, "etag"));
+        assert_eq!(fs::read_to_string(tmp.0.join("a.txt")).unwrap(), "two");
+        // POST answers `201 Created` either way
+        let posted = send(
+            &server,
+            "POST",
+            "/files/a.txt",
+            "Content-Length: 5\r\n\r\nthree",
+        )
+        .await;
+        assert_eq!(status_line(&posted), "HTTP/1.1 201 Created");
+        assert_eq!(fs::read_to_string(tmp.0.join("a.txt")).unwrap(), "three");
+    }
+
+    #[tokio::test]
+    async fn deletes_only_the_expected_version() {
+        let tmp = TempDir::new("server-delete");
+        let server = upload_server(&tmp, |uploads| uploads);
+        let created = send(
+            &server,
+            "PUT",
+            "/files/a.txt",
+            "Content-Length: 3\r\n\r\none",
+        )
+        .await;
+        let etag = header(&created, "etag").unwrap();
+
+        let stale = send(&server, "DELETE", "/files/a.txt", "If-Match: \"stale\"\r\n").await;
+        assert_eq!(status_line(&stale), "HTTP/1.1 412 Precondition Failed");
+        assert!(tmp.0.join("a.txt").exists());
+        let deleted = send(
+            &server,
+            "DELETE",
+            "/files/a.txt",
+            &format!("If-Match: {}\r\n", etag),
+        )
+        .await;
+        assert_eq!(status_line(&deleted), "HTTP/1.1 204 No Content");
+        assert!(!tmp.0.join("a.txt").exists());
+        let missing = send(&server, "DELETE", "/files/a.txt", "").await;
+        assert_eq!(status_line(&missing), "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn creates_directories_only_when_asked() {
+        let tmp = TempDir::new("server-create-dirs");
+        let server = upload_server(&tmp, |uploads| uploads);
+        let request = "Content-Length: 2\r\n\r\nhi";
+        let response = send(&server, "PUT", "/files/new/dir/a.txt", request).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+        assert!(!tmp.0.join("new").exists());
+
+        let server = upload_server(&tmp, |uploads| uploads.create_dirs(true));
+        let response = send(&server, "PUT", "/files/new/dir/a.txt", request).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 201 Created");
+        assert_eq!(
+            fs::read_to_string(tmp.0.join("new/dir/a.txt")).unwrap(),
+            "hi"
+        );
+        // Only files can be replaced
+        let response = send(&server, "PUT", "/files/new/dir", request).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 409 Conflict");
+    }
+
+    #[tokio::test]
+    async fn removes_the_temporary_file_of_failed_uploads() {
+        let tmp = TempDir::new("server-failed-upload");
+        fs::write(tmp.0.join("a.txt"), "old").unwrap();
+        let server = upload_server(&tmp, |uploads| uploads);
+        let too_large = format!("400\r\n{}\r\n1\r\nx\r\n0\r\n\r\n", "x".repeat(1024));
+        for (chunks, status) in [
+            ("3\r\nabc\r\nzz\r\n", "400 Bad Request"),
+            (too_large.as_str(), "413 Content Too Large"),
+        ] {
+            let request = format!("Transfer-Encoding: chunked\r\n\r\n{}", chunks);
+            let response = send(&server, "PUT", "/files/a.txt", &request).await;
+            assert_eq!(status_line(&response), format!("HTTP/1.1 {}", status));
+            let names = fs::read_dir(&tmp.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect_vec();
+            assert_eq!(names, ["a.txt"]);
+            assert_eq!(fs::read_to_string(tmp.0.join("a.txt")).unwrap(), "old");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_paths() {
+        let server = HttpServer::builder().get("/a", echo_path).build();
+        let response = get(&server, "/a%zz").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    fs::Metadata,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use tokio::fs::File;
+use tracing::debug;
+
+use super::{
+    body::Body,
+    conditional::Validators,
+    content_type::ContentType,
+    date,
+    etag::ETag,
+    header::Header,
+    listing::{self, Listing},
+    method::Method,
+    path,
+    range::{self, ByteRanges},
+    request::Request,
+    response::Response,
+    safe_path::{safe_join, SymlinkPolicy},
+    status_code::StatusCode,
+};
+
+/// Route parameter holding the path below the mount point.
+pub(super) const PATH_PARAM: &str = "static_path";
+
+/// Serves a directory tree, see
+/// [`HttpServerBuilder::static_files`](super::server::HttpServerBuilder::static_files).
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    /// File served for a directory, `index.html` by default.
+    index: Option<String>,
+    /// Whether files and directories whose name starts with `.` are served.
+    serve_hidden: bool,
+    symlinks: SymlinkPolicy,
+    /// Generate a listing for directories without an index.
+    listings: bool,
+    /// Entries per listing page.
+    page_size: usize,
+    /// Tag files with a hash of their content rather than their metadata.
+    strong_etags: bool,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        StaticFiles {
+            root: root.into(),
+            index: Some("index.html".to_string()),
+            serve_hidden: false,
+            symlinks: SymlinkPolicy::default(),
+            listings: false,
+            page_size: 100,
+            strong_etags: false,
+        }
+    }
+    /// File to serve when a directory is requested, `None` to disable.
+    #[allow(dead_code)]
+    pub fn index(mut self, index: Option<&str>) -> Self {
+        self.index = index.map(str::to_string);
+        self
+    }
+    /// Serve dotfiles (`.env`, `.git/`...). They answer `404 Not Found` by
+    /// default, as if they didn't exist.
+    #[allow(dead_code)]
+    pub fn serve_hidden(mut self, serve_hidden: bool) -> Self {
+        self.serve_hidden = serve_hidden;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// List the contents of directories that have no index file, as HTML or
+    /// as JSON for `Accept: application/json`. Disabled by default.
+    pub fn directory_listings(mut self, listings: bool) -> Self {
+        self.listings = listings;
+        self
+    }
+    /// Entries per listing page, 100 by default. Other pages are requested
+    /// with `?page=2`...
+    #[allow(dead_code)]
+    pub fn listing_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Tag files with a hash of their content instead of their size and
+    /// modification time. Each version of a file is read once more to hash
+    /// it, but strong tags work with `If-Match` and `If-Range`.
+    pub fn strong_etags(mut self, strong_etags: bool) -> Self {
+        self.strong_etags = strong_etags;
+        self
+    }
+
+    pub async fn serve(&self, request: Request) -> Response {
+        let path = request
+            .params
+            .get(PATH_PARAM)
+            .map(String::as_str)
+            .unwrap_or_default();
+        match self.serve_path(&request, path).await {
+            Ok(response) => response,
+            Err(status_code) => Response::builder().status_code(status_code).build(),
+        }
+    }
+
+    async fn serve_path(&self, request: &Request, path: &str) -> Result<Response, StatusCode> {
+        let full_path = self.resolve(path)?;
+        let metadata = std::fs::metadata(&full_path).map_err(|e| {
+            debug!("Unable to read metadata of {:?}: {}", full_path, e);
+            StatusCode::from(&e)
+        })?;
+        if metadata.is_file() {
+            // `file.txt/` names a directory that doesn't exist
+            if request.path.ends_with('/') {
+                return Err(StatusCode::NotFound);
+            }
+            return self.serve_file(request, &full_path).await;
+        }
+        if !metadata.is_dir() {
+            return Err(StatusCode::NotFound);
+        }
+        // Relative links in the index only work from `dir/`
+        if !request.path.ends_with('/') {
+            let location = match &request.query {
+                Some(query) => format!("{}/?{}", request.path, query),
+                None => format!("{}/", request.path),
+            };
+            return Ok(Response::builder()
+                .status_code(StatusCode::MovedPermanently)
+                .location(location)
+                .build());
+        }
+        if let Some(index) = &self.index {
+            // Resolved again so the index itself goes through the symlink checks
+            let index_path = self.resolve(&format!("{}/{}", path.trim_end_matches('/'), index));
+            if let Ok(index_path) = index_path {
+                if std::fs::metadata(&index_path).is_ok_and(|metadata| metadata.is_file()) {
+                    return self.serve_file(request, &index_path).await;
+                }
+            }
+        }
+        if !self.listings {
+            return Err(StatusCode::NotFound);
+        }
+        self.serve_listing(request, path, &full_path)
+    }
+
+    fn serve_listing(
+        &self,
+        request: &Request,
+        path: &str,
+        dir: &Path,
+    ) -> Result<Response, StatusCode> {
+        let content_type =
+            request.negotiate_content_type(&[ContentType::HTML, ContentType::JSON])?;
+        let page = match request.query_param("page") {
+            None => 1,
+            Some(page) => match page.parse::<usize>() {
+                Ok(page) if page > 0 => page,
+                _ => return Err(StatusCode::BadRequest),
+            },
+        };
+        let dir_path = path.trim_matches('/');
+        let entries = listing::read_entries(dir, |name| {
+            if !self.serve_hidden && name.starts_with('.') {
+                return false;
+            }
+            // Same checks as when the entry is requested
+            let entry_path = match dir_path.is_empty() {
+                true => name.to_string(),
+                false => format!("{}/{}", dir_path, name),
+            };
+            safe_join(&self.root, &entry_path, self.symlinks).is_ok()
+        })
+        .map_err(|e| StatusCode::from(&e))?;
+        let total = entries.len();
+        let pages = total.div_ceil(self.page_size).max(1);
+        if page > pages {
+            return Err(StatusCode::NotFound);
+        }
+        let listing = Listing {
+            path: path::percent_decode(&request.path).unwrap_or(request.path.clone()),
+            has_parent: !dir_path.is_empty(),
+            page,
+            pages,
+            total,
+            entries: entries
+                .into_iter()
+                .skip((page - 1) * self.page_size)
+                .take(self.page_size)
+                .collect(),
+        };
+        let body = match content_type.essence() {
+            "application/json" => listing.to_json(),
+            _ => listing.to_html(),
+        };
+        let mut response = Response::builder()
+            .content_type(content_type.with_charset("utf-8"))
+            .body(body.into_bytes())
+            .build();
+        response.vary(Header::Accept.to_str());
+        Ok(response)
+    }
+
+    /// Maps `path`, relative to the mount point, to a location inside the
+    /// root.
+    fn resolve(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        let path = path.trim_start_matches('/');
+        if !self.serve_hidden && path.split('/').any(|segment| segment.starts_with('.')) {
+            return Err(StatusCode::NotFound);
+        }
+        if path.trim_end_matches('/').is_empty() {
+            return self.root.canonicalize().map_err(|e| StatusCode::from(&e));
+        }
+        safe_join(&self.root, path, self.symlinks).map_err(|e| {
+            debug!("Refusing static path {:?}: {}", path, e);
+            e.status_code()
+        })
+    }
+
+    /// Sends the file, or the parts of it asked for with `Range`.
+    async fn serve_file(&self, request: &Request, path: &Path) -> Result<Response, StatusCode> {
+        let file = std::fs::File::open(path).map_err(|e| StatusCode::from(&e))?;
+        let metadata = file.metadata().map_err(|e| StatusCode::from(&e))?;
+        let len = metadata.len();
+        let content_type = ContentType::from_path(path);
+        let validators = Validators::from_file(&file, self.strong_etags)
+            .await
+            .map_err(|e| StatusCode::from(&e))?;
+        let etag = validators.etag.expect("files always get an entity tag");
+        let ranges = match request.headers.get(Header::Range.to_str()) {
+            // Range only applies to GET (RFC 9110 section 14.2)
+            Some(range)
+                if matches!(request.method, Method::Get)
+                    && if_range_matches(request, &etag, &metadata) =>
+            {
+                range::parse(range, len)
+            }
+            _ => ByteRanges::Ignore,
+        };
+        let mut response = Response::builder()
+            .header(Header::AcceptRanges.to_str(), "bytes")
+            .etag(&etag);
+        if let Some(last_modified) = validators.last_modified {
+            response = response.last_modified(last_modified);
+        }
+        let response = match ranges {
+            ByteRanges::Ignore => response
+                .content_type(content_type)
+                .body(Body::file(file, 0, len)),
+            ByteRanges::Unsatisfiable => response
+                .status_code(StatusCode::RangeNotSatisfiable)
+                .header(Header::ContentRange.to_str(), range::unsatisfied_range(len)),
+            ByteRanges::Satisfiable(ranges) if ranges.len() == 1 => {
+                let range = &ranges[0];
+                response
+                    .status_code(StatusCode::PartialContent)
+                    .content_type(content_type)
+                    .header(
+                        Header::ContentRange.to_str(),
+                        range::content_range(range, len),
+                    )
+                    .body(Body::file(
+                        file,
+                        *range.start(),
+                        range.end() - range.start() + 1,
+                    ))
+            }
+            ByteRanges::Satisfiable(ranges) => {
+                let (body, multipart_type) =
+                    range::multipart(File::from_std(file), ranges, len, &content_type);
+                response
+                    .status_code(StatusCode::PartialContent)
+                    .content_type(multipart_type)
+                    .body(body)
+            }
+        };
+        Ok(response.build())
+    }
+}
+
+/// `If-Range` makes a range request conditional: if the representation
+/// changed since the client's copy, it gets the whole thing instead
+/// (RFC 9110 section 13.1.5).
+fn if_range_matches(request: &Request, etag: &ETag, metadata: &Metadata) -> bool {
+    let Some(if_range) = request.headers.get(Header::IfRange.to_str()) else {
+        return true;
+    };
+    // Needs a strong match, so never true for the weak tags of files
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return ETag::from_str(if_range).is_ok_and(|if_range| if_range.strong_eq(etag));
+    }
+    match (date::parse(if_range), metadata.modified()) {
+        (Some(date), Ok(modified)) => date::format(modified) == date::format(date),
+        _ => false,
+    }
+}
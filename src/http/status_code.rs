@@ -1,17 +1,265 @@
-#[derive(Copy, Clone)]
-pub enum StatusCode {
-    Ok,
-    Created,
-    NotFound,
-    InternalServerError,
+use std::fmt::Display;
+
+macro_rules! status_codes {
+    ($(($code:literal, $name:ident, $reason:literal),)+) => {
+        /// Status codes from the IANA HTTP Status Code Registry. Any other code
+        /// in the 100..=599 range can be sent with [`StatusCode::custom`].
+        #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+        pub enum StatusCode {
+            $($name,)+
+            /// An unregistered code with its reason phrase.
+            Custom(CustomStatus),
+        }
+
+        impl StatusCode {
+            pub fn code(self) -> u16 {
+                match self {
+                    $(StatusCode::$name => $code,)+
+                    StatusCode::Custom(custom) => custom.code,
+                }
+            }
+            pub fn reason(self) -> &'static str {
+                match self {
+                    $(StatusCode::$name => $reason,)+
+                    StatusCode::Custom(custom) => custom.reason,
+                }
+            }
+            fn from_registry(code: u16) -> Option<Self> {
+                match code {
+                    $($code => Some(StatusCode::$name),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    (100, Continue, "Continue"),
+    (101, SwitchingProtocols, "Switching Protocols"),
+    (102, Processing, "Processing"),
+    (103, EarlyHints, "Early Hints"),
+    (200, Ok, "OK"),
+    (201, Created, "Created"),
+    (202, Accepted, "Accepted"),
+    (203, NonAuthoritativeInformation, "Non-Authoritative Information"),
+    (204, NoContent, "No Content"),
+    (205, ResetContent, "Reset Content"),
+    (206, PartialContent, "Partial Content"),
+    (207, MultiStatus, "Multi-Status"),
+    (208, AlreadyReported, "Already Reported"),
+    (226, ImUsed, "IM Used"),
+    (300, MultipleChoices, "Multiple Choices"),
+    (301, MovedPermanently, "Moved Permanently"),
+    (302, Found, "Found"),
+    (303, SeeOther, "See Other"),
+    (304, NotModified, "Not Modified"),
+    (305, UseProxy, "Use Proxy"),
+    (307, TemporaryRedirect, "Temporary Redirect"),
+    (308, PermanentRedirect, "Permanent Redirect"),
+    (400, BadRequest, "Bad Request"),
+    (401, Unauthorized, "Unauthorized"),
+    (402, PaymentRequired, "Payment Required"),
+    (403, Forbidden, "Forbidden"),
+    (404, NotFound, "Not Found"),
+    (405, MethodNotAllowed, "Method Not Allowed"),
+    (406, NotAcceptable, "Not Acceptable"),
+    (407, ProxyAuthenticationRequired, "Proxy Authentication Required"),
+    (408, RequestTimeout, "Request Timeout"),
+    (409, Conflict, "Conflict"),
+    (410, Gone, "Gone"),
+    (411, LengthRequired, "Length Required"),
+    (412, PreconditionFailed, "Precondition Failed"),
+    (413, ContentTooLarge, "Content Too Large"),
+    (414, UriTooLong, "URI Too Long"),
+    (415, UnsupportedMediaType, "Unsupported Media Type"),
+    (416, RangeNotSatisfiable, "Range Not Satisfiable"),
+    (417, ExpectationFailed, "Expectation Failed"),
+    (421, MisdirectedRequest, "Misdirected Request"),
+    (422, UnprocessableContent, "Unprocessable Content"),
+    (423, Locked, "Locked"),
+    (424, FailedDependency, "Failed Dependency"),
+    (425, TooEarly, "Too Early"),
+    (426, UpgradeRequired, "Upgrade Required"),
+    (428, PreconditionRequired, "Precondition Required"),
+    (429, TooManyRequests, "Too Many Requests"),
+    (431, RequestHeaderFieldsTooLarge, "Request Header Fields Too Large"),
+    (451, UnavailableForLegalReasons, "Unavailable For Legal Reasons"),
+    (500, InternalServerError, "Internal Server Error"),
+    (501, NotImplemented, "Not Implemented"),
+    (502, BadGateway, "Bad Gateway"),
+    (503, ServiceUnavailable, "Service Unavailable"),
+    (504, GatewayTimeout, "Gateway Timeout"),
+    (505, HttpVersionNotSupported, "HTTP Version Not Supported"),
+    (506, VariantAlsoNegotiates, "Variant Also Negotiates"),
+    (507, InsufficientStorage, "Insufficient Storage"),
+    (508, LoopDetected, "Loop Detected"),
+    (511, NetworkAuthenticationRequired, "Network Authentication Required"),
+}
+
+/// Code and reason phrase of a [`StatusCode::Custom`], only built by
+/// [`StatusCode::custom`] so the status line is always valid.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CustomStatus {
+    code: u16,
+    reason: &'static str,
 }
+
 impl StatusCode {
-    pub fn status_line<'a>(&self) -> &'a str {
-        match self {
-            StatusCode::Ok => "200 OK",
-            StatusCode::Created => "201 Created",
-            StatusCode::NotFound => "404 Not Found",
-            StatusCode::InternalServerError => "500 Internal Server Error",
+    /// Status `code` with `reason` as reason phrase. Registered codes give
+    /// their own variant and keep their usual phrase. Fails for codes outside
+    /// 100..=599 (RFC 9110 section 15) and phrases with control characters.
+    #[allow(dead_code)]
+    pub fn custom(code: u16, reason: &'static str) -> Result<Self, String> {
+        if !(100..=599).contains(&code) {
+            return Err(format!("Invalid status code {}", code));
+        }
+        // reason-phrase = 1*( HTAB / SP / VCHAR / obs-text )
+        if reason
+            .bytes()
+            .any(|b| b != b'\t' && (b < b' ' || b == 0x7f))
+        {
+            return Err(format!("Invalid reason phrase {:?}", reason));
+        }
+        Ok(StatusCode::from_registry(code)
+            .unwrap_or(StatusCode::Custom(CustomStatus { code, reason })))
+    }
+    /// `200 OK`, the part of the status line after the version.
+    pub fn status_line(&self) -> String {
+        format!("{} {}", self.code(), self.reason())
+    }
+    /// 1xx
+    pub fn is_informational(self) -> bool {
+        (100..200).contains(&self.code())
+    }
+    /// 2xx
+    #[allow(dead_code)]
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.code())
+    }
+    /// 3xx
+    #[allow(dead_code)]
+    pub fn is_redirect(self) -> bool {
+        (300..400).contains(&self.code())
+    }
+    /// 4xx
+    #[allow(dead_code)]
+    pub fn is_client_error(self) -> bool {
+        (400..500).contains(&self.code())
+    }
+    /// 5xx
+    #[allow(dead_code)]
+    pub fn is_server_error(self) -> bool {
+        (500..600).contains(&self.code())
+    }
+    /// Responses with these codes never have content (RFC 9110 section 6.4.1),
+    /// whatever the handler put in the body.
+    pub fn allows_body(self) -> bool {
+        !(self.is_informational() || matches!(self.code(), 204 | 304))
+    }
+}
+
+impl Display for StatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.status_line())
+    }
+}
+
+impl From<StatusCode> for u16 {
+    fn from(status_code: StatusCode) -> Self {
+        status_code.code()
+    }
+}
+
+impl TryFrom<u16> for StatusCode {
+    type Error = String;
+
+    /// Registered codes map to their variant, any other code in 100..=599
+    /// becomes a [`StatusCode::Custom`] without a reason phrase.
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        StatusCode::custom(code, "")
+    }
+}
+
+impl From<&std::io::Error> for StatusCode {
+    fn from(e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => StatusCode::NotFound,
+            std::io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+            _ => StatusCode::InternalServerError,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_codes() {
+        for code in 100..=599 {
+            let status_code = StatusCode::try_from(code).unwrap();
+            assert_eq!(u16::from(status_code), code);
+        }
+        assert_eq!(StatusCode::try_from(404), Ok(StatusCode::NotFound));
+        assert_eq!(StatusCode::try_from(299).unwrap().status_line(), "299 ");
+        for code in [0, 42, 99, 600, 999, 1000] {
+            assert!(StatusCode::try_from(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn builds_custom_codes() {
+        let teapot = StatusCode::custom(418, "I'm a teapot").unwrap();
+        assert_eq!(teapot.status_line(), "418 I'm a teapot");
+        assert_eq!(teapot.to_string(), "418 I'm a teapot");
+        // Registered codes keep their variant
+        assert_eq!(
+            StatusCode::custom(204, "Nothing"),
+            Ok(StatusCode::NoContent)
+        );
+        assert!(StatusCode::custom(42, "").is_err());
+        assert!(StatusCode::custom(600, "").is_err());
+        assert!(StatusCode::custom(499, "Bad\r\nX-Injected: 1").is_err());
+        assert!(StatusCode::custom(499, "Tab\tis fine").is_ok());
+    }
+
+    #[test]
+    fn classifies_codes() {
+        let class = |code: u16| {
+            let status_code = StatusCode::try_from(code).unwrap();
+            [
+                status_code.is_informational(),
+                status_code.is_success(),
+                status_code.is_redirect(),
+                status_code.is_client_error(),
+                status_code.is_server_error(),
+            ]
+            .iter()
+            .position(|is| *is)
+        };
+        for (code, expected) in [(100, 0), (199, 0), (200, 1), (308, 2), (451, 3), (599, 4)] {
+            assert_eq!(class(code), Some(expected), "{}", code);
+        }
+    }
+
+    #[test]
+    fn knows_which_codes_have_no_content() {
+        for code in [100, 101, 103, 150, 204, 304] {
+            assert!(
+                !StatusCode::try_from(code).unwrap().allows_body(),
+                "{}",
+                code
+            );
+        }
+        for code in [200, 205, 206, 301, 404, 500] {
+            assert!(
+                StatusCode::try_from(code).unwrap().allows_body(),
+                "{}",
+                code
+            );
         }
     }
 }
@@ -0,0 +1,20 @@
+use std::{fs, path::PathBuf};
+
+/// A directory under the system temp dir, removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    /// `name` must be unique among the tests running in parallel.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("http-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("unable to create temp dir");
+        TempDir(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
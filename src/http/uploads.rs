@@ -0,0 +1,205 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tracing::debug;
+
+use super::{
+    conditional::{Conditions, Validators},
+    etag::{ETag, Fnv},
+    request::Request,
+    response::Response,
+    safe_path::{safe_join, SymlinkPolicy},
+    status_code::StatusCode,
+};
+use crate::utils::atomic_file::AtomicFile;
+
+/// Route parameter holding the file name below the mount point.
+pub(super) const FILE_PARAM: &str = "file_name";
+
+/// Writes uploaded files to a directory and deletes them, see
+/// [`HttpServerBuilder::uploads`](super::server::HttpServerBuilder::uploads).
+#[derive(Debug)]
+pub struct Uploads {
+    root: PathBuf,
+    /// Create missing directories for uploads to `dir/file`.
+    create_dirs: bool,
+    /// Tag files with a hash of their content rather than their metadata.
+    strong_etags: bool,
+    /// Held by uploads and deletes while they check their preconditions and
+    /// change the file.
+    lock: Mutex<()>,
+}
+
+impl Uploads {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Uploads {
+            root: root.into(),
+            create_dirs: false,
+            strong_etags: true,
+            lock: Mutex::new(()),
+        }
+    }
+    /// Whether uploads to `dir/file` create `dir` when it doesn't exist.
+    /// Disabled by default.
+    pub fn create_dirs(mut self, create_dirs: bool) -> Self {
+        self.create_dirs = create_dirs;
+        self
+    }
+    /// Tag files with a hash of their content, which `If-Match` needs, or
+    /// from their metadata. Should match the
+    /// [`StaticFiles`](super::static_files::StaticFiles) serving the same
+    /// directory.
+    pub fn strong_etags(mut self, strong_etags: bool) -> Self {
+        self.strong_etags = strong_etags;
+        self
+    }
+
+    /// Creates or replaces the file, `201 Created` either way.
+    pub async fn post(&self, mut request: Request) -> Response {
+        match self.upload(&mut request).await {
+            Ok((_, etag)) => Response::builder()
+                .status_code(StatusCode::Created)
+                .etag(&etag)
+                .build(),
+            Err(status_code) => Response::builder().status_code(status_code).build(),
+        }
+    }
+
+    /// Creates or replaces the file: `201 Created` or `204 No Content`.
+    pub async fn put(&self, mut request: Request) -> Response {
+        match self.upload(&mut request).await {
+            Ok((created, etag)) => Response::builder()
+                .status_code(match created {
+                    true => StatusCode::Created,
+                    false => StatusCode::NoContent,
+                })
+                .etag(&etag)
+                .build(),
+            Err(status_code) => Response::builder().status_code(status_code).build(),
+        }
+    }
+
+    pub async fn delete(&self, request: Request) -> Response {
+        let mut res_builder = Response::builder();
+        let file_path = match self.resolve(&request) {
+            Ok(file_path) => file_path,
+            Err(status_code) => return res_builder.status_code(status_code).build(),
+        };
+        let conditions = Conditions::from_request(&request);
+        let _lock = self.lock.lock().await;
+        let current = match self.current_validators(&file_path).await {
+            Ok(Some(current)) => current,
+            Ok(None) => return res_builder.status_code(StatusCode::NotFound).build(),
+            Err(status_code) => return res_builder.status_code(status_code).build(),
+        };
+        if conditions.precondition_failed(Some(&current)) {
+            return res_builder
+                .status_code(StatusCode::PreconditionFailed)
+                .build();
+        }
+        debug!("Deleting file: {:?}", file_path);
+        res_builder = match tokio::fs::remove_file(&file_path).await {
+            Ok(_) => res_builder.status_code(StatusCode::NoContent),
+            Err(e) => {
+                debug!("Unable to delete file: {}", e);
+                res_builder.status_code(StatusCode::from(&e))
+            }
+        };
+        res_builder.build()
+    }
+
+    /// Writes the request body to the file named in the route, atomically.
+    /// Returns whether the file was created and its new entity tag.
+    async fn upload(&self, request: &mut Request) -> Result<(bool, ETag), StatusCode> {
+        let file_path = self.resolve(request)?;
+        let conditions = Conditions::from_request(request);
+        // Checked once before receiving the body, so a doomed upload isn't sent
+        if conditions.precondition_failed(self.current_validators(&file_path).await?.as_ref()) {
+            debug!("Precondition failed for upload to {:?}", file_path);
+            return Err(StatusCode::PreconditionFailed);
+        }
+        if self.create_dirs {
+            if let Some(parent) = file_path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| StatusCode::from(&e))?;
+            }
+        }
+        debug!("Writing file to: {:?}", file_path);
+        let mut file = AtomicFile::create(&file_path).await.map_err(|e| {
+            debug!("Unable to create file: {}", e);
+            StatusCode::from(&e)
+        })?;
+        // Hashed as it arrives, rather than reading the file back
+        let mut hasher = Fnv::default();
+        loop {
+            let chunk = request.body.chunk().await.map_err(|e| {
+                debug!("Unable to receive upload: {}", e);
+                e.status_code()
+            })?;
+            let Some(chunk) = chunk else {
+                break;
+            };
+            hasher.update(&chunk);
+            file.file().write_all(&chunk).await.map_err(|e| {
+                debug!("Unable to write upload: {}", e);
+                StatusCode::from(&e)
+            })?;
+        }
+
+        // And again right before replacing the file, under a lock so two
+        // uploads conditional on the same version can't both go through
+        let _lock = self.lock.lock().await;
+        let current = self.current_validators(&file_path).await?;
+        if conditions.precondition_failed(current.as_ref()) {
+            debug!("Precondition failed for upload to {:?}", file_path);
+            return Err(StatusCode::PreconditionFailed);
+        }
+        file.persist().await.map_err(|e| {
+            debug!("Unable to move upload in place: {}", e);
+            StatusCode::from(&e)
+        })?;
+        let metadata = std::fs::metadata(&file_path).map_err(|e| StatusCode::from(&e))?;
+        let etag = match self.strong_etags {
+            true => {
+                let etag = hasher.etag();
+                ETag::remember(&metadata, &etag);
+                etag
+            }
+            false => ETag::from_metadata(&metadata),
+        };
+        Ok((current.is_none(), etag))
+    }
+
+    /// Validators of the file as it is now, `None` if it doesn't exist.
+    async fn current_validators(&self, file_path: &Path) -> Result<Option<Validators>, StatusCode> {
+        match std::fs::metadata(file_path) {
+            // Only files can be replaced or deleted
+            Ok(metadata) if metadata.is_dir() => return Err(StatusCode::Conflict),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StatusCode::from(&e)),
+        }
+        let file = std::fs::File::open(file_path).map_err(|e| StatusCode::from(&e))?;
+        Validators::from_file(&file, self.strong_etags)
+            .await
+            .map(Some)
+            .map_err(|e| StatusCode::from(&e))
+    }
+
+    /// Resolves the file name route param inside of the root, refusing
+    /// anything that would end up outside of it.
+    fn resolve(&self, request: &Request) -> Result<PathBuf, StatusCode> {
+        let file_name = request
+            .params
+            .get(FILE_PARAM)
+            .expect("file name should be available");
+        safe_join(&self.root, file_name, SymlinkPolicy::default()).map_err(|e| {
+            debug!("Refusing file name {:?}: {}", file_name, e);
+            e.status_code()
+        })
+    }
+}
@@ -1,12 +1,9 @@
 #![warn(clippy::all)]
 #![warn(opaque_hidden_inferred_bound)]
 
-use std::{collections::HashMap, str::FromStr, sync::OnceLock};
+use std::{collections::HashMap, sync::OnceLock};
 
-use http::{
-    content_type::ContentType, encoding::Encoding, header::Header, request::Request,
-    status_code::StatusCode,
-};
+use http::{header::Header, request::Request, static_files::StaticFiles, uploads::Uploads};
 use tracing::info;
 
 use crate::{
@@ -25,105 +22,79 @@ async fn main() {
     setup();
     info!("Logs from your program will appear here!");
     parse_cmd_args();
-    HttpServer::builder()
+    let mut server = HttpServer::builder()
+        // Even the tiny echo responses are expected to come back compressed
+        .compression_min_size(0)
+        .decompress_requests(true)
+        // `--sendfile false` copies through userspace, to compare
+        .sendfile(
+            ARGS.get()
+                .expect("ARGS should already be set")
+                .get("--sendfile")
+                .map_or(true, |sendfile| sendfile != "false"),
+        )
         .get("/", root)
         .get("/echo/:message", echo_route)
-        .get("/user-agent", user_agent)
-        .get("/files/:file_name", file_route)
-        .post("/files/:file_name", file_route_post)
-        .start()
-        .await
-        .expect("unable to start server");
+        .get("/user-agent", user_agent);
+    if let Some(dir_name) = ARGS
+        .get()
+        .expect("ARGS should already be set")
+        .get("--directory")
+    {
+        server = server
+            .uploads(
+                "/files",
+                Uploads::new(dir_name)
+                    .create_dirs(create_dirs())
+                    .strong_etags(strong_etags()),
+            )
+            .static_files(
+                "/files",
+                StaticFiles::new(dir_name)
+                    .directory_listings(true)
+                    // So uploads can be made conditional on what was downloaded
+                    .strong_etags(strong_etags()),
+            );
+    }
+    server.start().await.expect("unable to start server");
 }
 
-fn root(_: Request) -> Response {
+async fn root(_: Request) -> Response {
     Response::builder().build()
 }
 
-fn echo_route(request: Request) -> Response {
+async fn echo_route(request: Request) -> Response {
     let body = request
         .params
         .get("message")
         .map(|msg| msg.as_bytes().to_vec());
-
-    // TODO(Aman): Can this be put when we parse request??
-    let accept_encoding = request
-        .headers
-        .get(Header::AcceptEncoding.to_str())
-        .and_then(|value| {
-            value
-                .split(",")
-                .find_map(|encoding| match Encoding::from_str(encoding.trim()) {
-                    Ok(accept_encoding) => Some(accept_encoding),
-                    Err(_) => None,
-                })
-        });
-    Response::builder()
-        .body(body)
-        .accept_encoding(accept_encoding)
-        .build()
+    Response::builder().body(body).build()
 }
-fn user_agent(req: Request) -> Response {
+async fn user_agent(req: Request) -> Response {
     let body = req
         .headers
         .get(Header::UserAgent.to_str())
         .map(|msg| msg.as_bytes().to_vec());
     Response::builder().body(body).build()
 }
-fn file_route(req: Request) -> Response {
-    let mut res_builder = Response::builder();
-    let file_name = req
-        .params
-        .get("file_name")
-        .expect("file_name should be available");
 
-    match ARGS
-        .get()
+/// Whether uploads to `dir/file` create `dir` when it doesn't exist, set
+/// with `--create-dirs true`.
+fn create_dirs() -> bool {
+    ARGS.get()
         .expect("ARGS should already be set")
-        .get("--directory")
-    {
-        None => {
-            res_builder = res_builder.status_code(StatusCode::NotFound);
-        }
-        Some(dir_name) => match std::fs::read_to_string(format!("{}/{}", dir_name, file_name)) {
-            Err(_) => {
-                res_builder = res_builder.status_code(StatusCode::NotFound);
-            }
-            Ok(file_content) => {
-                res_builder = res_builder
-                    .status_code(StatusCode::Ok)
-                    .content_type(ContentType::OctetStream)
-                    .body(Some(file_content.as_bytes().to_vec()))
-            }
-        },
-    }
-    res_builder.build()
+        .get("--create-dirs")
+        .is_some_and(|create_dirs| create_dirs == "true")
 }
-fn file_route_post(req: Request) -> Response {
-    let mut res_builder = Response::builder();
-    let file_name = req
-        .params
-        .get("file_name")
-        .expect("file_name should be available");
 
-    match ARGS
-        .get()
+/// Whether files are tagged with a hash of their content, which
+/// `If-Match` needs. `--strong-etags false` tags them from their metadata
+/// instead, e.g. to benchmark sending files without hashing them.
+fn strong_etags() -> bool {
+    ARGS.get()
         .expect("ARGS should already be set")
-        .get("--directory")
-    {
-        None => res_builder = res_builder.status_code(StatusCode::InternalServerError),
-        Some(dir_name) => {
-            tracing::debug!(
-                "Writing file to: {}/{}, body: {:?}",
-                dir_name,
-                file_name,
-                &req.body
-            );
-            std::fs::write(format!("{}/{}", dir_name, file_name), &req.body).unwrap();
-            res_builder = res_builder.status_code(StatusCode::Created);
-        }
-    }
-    res_builder.build()
+        .get("--strong-etags")
+        .map_or(true, |strong_etags| strong_etags != "false")
 }
 
 fn parse_cmd_args() {
@@ -0,0 +1,76 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tracing::debug;
+
+/// A file written under a temporary name next to its destination and moved
+/// in place once complete, so readers see either the old or the new content,
+/// never half of it. Dropping it without [`AtomicFile::persist`] removes the
+/// temporary file.
+pub struct AtomicFile {
+    file: File,
+    temp_path: PathBuf,
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl AtomicFile {
+    pub async fn create(path: &Path) -> std::io::Result<Self> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| Error::from(ErrorKind::InvalidInput))?
+            .to_string_lossy();
+        // Same directory so the rename can't cross file systems, dotted so
+        // it isn't served or listed meanwhile
+        let temp_path = path.with_file_name(format!(
+            ".{}.{}-{}.tmp",
+            file_name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .await?;
+        Ok(AtomicFile {
+            file,
+            temp_path,
+            path: path.to_path_buf(),
+            persisted: false,
+        })
+    }
+
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Flushes the content to disk and moves it to the destination,
+    /// replacing whatever was there.
+    pub async fn persist(mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        tokio::fs::rename(&self.temp_path, &self.path).await?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.persisted {
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(&self.temp_path) {
+            debug!("Unable to remove {:?}: {}", self.temp_path, e);
+        }
+    }
+}